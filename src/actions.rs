@@ -0,0 +1,123 @@
+//! Sending OSC messages to other devices, as configured in `[targets]` and
+//! `[actions]`.
+
+use std::collections::HashMap;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use rosc::{OscMessage, OscPacket, OscType, self};
+use config::Config;
+use pattern::{self, Pattern};
+use ctxt::CommandError;
+use failure::Error;
+
+/// The file states that can have actions attached to them (see `Context::notify_file`).
+pub const FILE_EVENTS: &[&str] = &["loaded", "started", "stopped", "fading", "finished", "looped", "relocated"];
+
+/// One OSC message that an action sends.
+struct OutMessage {
+    target: SocketAddr,
+    msg: OscMessage
+}
+
+/// Named actions, which send OSC messages to other devices.
+pub struct Actions {
+    actions: HashMap<String, Vec<OutMessage>>,
+    sock_v4: Option<UdpSocket>,
+    sock_v6: Option<UdpSocket>
+}
+impl Actions {
+    /// Resolves the targets and actions in `cfg`, and checks that everything
+    /// that refers to an action refers to one that exists.
+    pub fn new(cfg: &Config) -> Result<Self, Error> {
+        let mut targets = HashMap::new();
+        for (name, addr) in cfg.targets.iter() {
+            let addr = addr.to_socket_addrs()
+                .map_err(|e| format_err!("invalid address for target '{}': {}", name, e))?
+                .next()
+                .ok_or(format_err!("address for target '{}' didn't resolve", name))?;
+            targets.insert(name.clone(), addr);
+        }
+        let mut actions = HashMap::new();
+        for (name, sends) in cfg.actions.iter() {
+            let mut ret = vec![];
+            for send in sends.to_vec() {
+                let target = *targets.get(&send.target)
+                    .ok_or(format_err!("action '{}' refers to unknown target '{}'", name, send.target))?;
+                if !send.addr.starts_with('/') {
+                    bail!("action '{}': address '{}' should start with a /", name, send.addr);
+                }
+                let args = send.args.iter().map(|a| a.to_osc()).collect::<Vec<_>>();
+                ret.push(OutMessage {
+                    target,
+                    msg: OscMessage {
+                        addr: send.addr.clone(),
+                        args: if !args.is_empty() { Some(args) } else { None }
+                    }
+                });
+            }
+            actions.insert(name.clone(), ret);
+        }
+        for (file, pf) in cfg.files.iter() {
+            for (event, names) in pf.on.iter() {
+                if !FILE_EVENTS.contains(&(event as &str)) && !event.starts_with("marker:") {
+                    bail!("file '{}' has actions for unknown event '{}'", file, event);
+                }
+                for name in names.to_vec() {
+                    if !actions.contains_key(&name) {
+                        bail!("file '{}' refers to unknown action '{}'", file, name);
+                    }
+                }
+            }
+        }
+        let (mut v4, mut v6) = (false, false);
+        for send in actions.values().flat_map(|x| x.iter()) {
+            match send.target {
+                SocketAddr::V4(_) => v4 = true,
+                SocketAddr::V6(_) => v6 = true
+            }
+        }
+        let sock_v4 = if v4 { Some(UdpSocket::bind("0.0.0.0:0")?) } else { None };
+        let sock_v6 = if v6 { Some(UdpSocket::bind("[::]:0")?) } else { None };
+        Ok(Actions { actions, sock_v4, sock_v6 })
+    }
+    /// Resolves the OSC address pattern `pat` to a list of action names.
+    pub fn resolve(&self, pat: &str) -> Vec<String> {
+        if !pattern::is_pattern(pat) {
+            return vec![pat.into()];
+        }
+        let pat = Pattern::new(pat);
+        let mut ret = self.actions.keys()
+            .filter(|name| pat.matches(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        ret.sort();
+        ret
+    }
+    /// The names of every action.
+    pub fn names(&self) -> Vec<String> {
+        let mut ret = self.actions.keys().cloned().collect::<Vec<_>>();
+        ret.sort();
+        ret
+    }
+    /// Runs the action `name`, appending `extra_args` to every message it sends.
+    pub fn fire(&self, name: &str, extra_args: &[OscType]) -> Result<(), Error> {
+        let sends = self.actions.get(name)
+            .ok_or(CommandError::NotFound("No such action.".into()))?;
+        info!("Running action '{}'", name);
+        for send in sends.iter() {
+            let mut msg = send.msg.clone();
+            if !extra_args.is_empty() {
+                msg.args.get_or_insert(vec![]).extend(extra_args.iter().cloned());
+            }
+            let buf = rosc::encoder::encode(&OscPacket::Message(msg))
+                .map_err(|e| format_err!("{:?}", e))?;
+            let sock = match send.target {
+                SocketAddr::V4(_) => self.sock_v4.as_ref(),
+                SocketAddr::V6(_) => self.sock_v6.as_ref()
+            };
+            // There's always a socket for every kind of target we have.
+            sock.unwrap().send_to(&buf, send.target)
+                .map_err(|e| format_err!("failed sending to {}: {}", send.target, e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,282 @@
+//! Access control: source address allow-lists, and shared-secret tokens.
+
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rosc::{OscMessage, OscType};
+use config::Config;
+use failure::Error;
+
+/// A range of IP addresses, like `10.0.0.0/8`.
+#[derive(Debug, Clone)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8
+}
+impl Cidr {
+    /// Parses either a CIDR range, or a single address.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut split = s.trim().splitn(2, '/');
+        let addr: IpAddr = split.next().unwrap().parse()
+            .map_err(|e| format_err!("invalid address '{}': {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match split.next() {
+            Some(p) => p.parse::<u8>()
+                .map_err(|e| format_err!("invalid prefix length in '{}': {}", s, e))?,
+            None => max
+        };
+        if prefix > max {
+            bail!("prefix length in '{}' is too long", s);
+        }
+        Ok(Cidr { addr, prefix })
+    }
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, unmap_v4(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix) };
+                u32::from(net) & mask == u32::from(addr) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u128 << (128 - self.prefix) };
+                u128::from(net) & mask == u128::from(addr) & mask
+            },
+            _ => false
+        }
+    }
+}
+/// Turns IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`), which you get when
+/// listening on an IPv6 socket, back into IPv4 ones.
+fn unmap_v4(addr: IpAddr) -> IpAddr {
+    if let IpAddr::V6(v6) = addr {
+        let segs = v6.segments();
+        if segs[0..5] == [0, 0, 0, 0, 0] && segs[5] == 0xffff {
+            if let Some(v4) = v6.to_ipv4() {
+                return IpAddr::V4(v4);
+            }
+        }
+    }
+    addr
+}
+/// Compares two tokens, taking the same time however much of them matches, so
+/// that the token can't be worked out by timing how long rejections take.
+fn tokens_match(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Decides which commands we should listen to, and counts the ones we don't.
+///
+/// The allow-list and token can be changed (when the config is reloaded) while
+/// it's in use.
+pub struct AccessControl {
+    allow: RwLock<Vec<Cidr>>,
+    token: RwLock<Option<String>>,
+    rejected: AtomicUsize
+}
+impl AccessControl {
+    pub fn new(cfg: &Config) -> Result<Self, Error> {
+        let allow = cfg.allow.iter()
+            .map(|x| Cidr::parse(x))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(AccessControl {
+            allow: RwLock::new(allow),
+            token: RwLock::new(cfg.token.clone()),
+            rejected: AtomicUsize::new(0)
+        })
+    }
+    /// Makes an `AccessControl` that lets everything through, for commands that
+    /// come from inside the server (e.g. MIDI bindings).
+    pub fn unrestricted() -> Self {
+        AccessControl {
+            allow: RwLock::new(vec![]),
+            token: RwLock::new(None),
+            rejected: AtomicUsize::new(0)
+        }
+    }
+    /// Switches over to the allow-list and token from `new`, keeping count of
+    /// rejected commands.
+    pub fn replace(&self, new: AccessControl) {
+        *self.allow.write().unwrap() = new.allow.into_inner().unwrap();
+        *self.token.write().unwrap() = new.token.into_inner().unwrap();
+    }
+    /// Logs and counts a rejected command.
+    pub fn reject(&self, from: &str, why: &str) {
+        let n = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Rejected command from {}: {} ({} rejected so far)", from, why, n);
+    }
+    /// How many commands have been rejected so far.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+    /// Checks whether `addr` is on the allow-list (if there is one).
+    pub fn source_allowed(&self, addr: IpAddr) -> bool {
+        let allow = self.allow.read().unwrap();
+        allow.is_empty() || allow.iter().any(|c| c.contains(addr))
+    }
+    /// Checks whether `msg` carries the right token (if one is required), and
+    /// strips it off if so.
+    ///
+    /// The token can either be the first argument, or the first part of the
+    /// address (i.e. `/TOKEN/file/...`).
+    pub fn check_token(&self, msg: &mut OscMessage) -> bool {
+        let token = self.token.read().unwrap();
+        let token = match *token {
+            Some(ref t) => t,
+            None => return true
+        };
+        let addr = msg.addr.as_bytes();
+        let len = token.len();
+        if addr.len() > len + 1 && addr[0] == b'/' && addr[len + 1] == b'/' && tokens_match(&addr[1..len + 1], token.as_bytes()) {
+            msg.addr = msg.addr[len + 1..].to_string();
+            return true;
+        }
+        let first_matches = match msg.args.as_ref().and_then(|a| a.first()) {
+            Some(OscType::String(s)) => tokens_match(s.as_bytes(), token.as_bytes()),
+            _ => false
+        };
+        if first_matches {
+            if let Some(ref mut args) = msg.args {
+                args.remove(0);
+            }
+            return true;
+        }
+        false
+    }
+    /// Checks an HTTP-style bearer token (the value of an `Authorization` header).
+    pub fn check_bearer(&self, header: Option<&str>) -> bool {
+        match *self.token.read().unwrap() {
+            Some(ref t) => {
+                match header.map(|h| h.trim()) {
+                    Some(h) if h.starts_with("Bearer ") => tokens_match(&h.as_bytes()["Bearer ".len()..], t.as_bytes()),
+                    _ => false
+                }
+            },
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+    fn with_token(token: &str) -> AccessControl {
+        AccessControl {
+            allow: RwLock::new(vec![]),
+            token: RwLock::new(Some(token.into())),
+            rejected: AtomicUsize::new(0)
+        }
+    }
+    fn msg(addr: &str, args: Vec<OscType>) -> OscMessage {
+        OscMessage { addr: addr.into(), args: Some(args) }
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert!(Cidr::parse("10.0.0.0/8").is_ok());
+        assert!(Cidr::parse(" 192.168.1.5 ").is_ok());
+        assert!(Cidr::parse("fd00::/8").is_ok());
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("fd00::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+        assert!(Cidr::parse("cake").is_err());
+    }
+    #[test]
+    fn cidr_contains() {
+        let net = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("10.2.2.3")));
+        let single = Cidr::parse("192.168.1.5").unwrap();
+        assert!(single.contains(ip("192.168.1.5")));
+        assert!(!single.contains(ip("192.168.1.6")));
+        let all = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(all.contains(ip("203.0.113.9")));
+        assert!(!all.contains(ip("2001:db8::1")));
+        let v6 = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.1.2.3")));
+    }
+    #[test]
+    fn ipv4_mapped() {
+        let mapped = IpAddr::V6(Ipv4Addr::new(10, 1, 2, 3).to_ipv6_mapped());
+        assert_eq!(unmap_v4(mapped), ip("10.1.2.3"));
+        assert!(Cidr::parse("10.1.0.0/16").unwrap().contains(mapped));
+        assert!(Cidr::parse("127.0.0.1").unwrap().contains(ip("::ffff:127.0.0.1")));
+        // IPv4-compatible addresses (without the ffff) aren't IPv4.
+        let compat = IpAddr::V6(Ipv4Addr::new(10, 1, 2, 3).to_ipv6_compatible());
+        assert_eq!(unmap_v4(compat), compat);
+        assert_eq!(unmap_v4(IpAddr::V6(Ipv6Addr::LOCALHOST)), ip("::1"));
+    }
+    #[test]
+    fn source_allowed() {
+        let acl = AccessControl::unrestricted();
+        assert!(acl.source_allowed(ip("203.0.113.9")));
+        *acl.allow.write().unwrap() = vec![Cidr::parse("10.0.0.0/8").unwrap(), Cidr::parse("::1").unwrap()];
+        assert!(acl.source_allowed(ip("10.9.9.9")));
+        assert!(acl.source_allowed(ip("::ffff:10.9.9.9")));
+        assert!(acl.source_allowed(ip("::1")));
+        assert!(!acl.source_allowed(ip("203.0.113.9")));
+    }
+    #[test]
+    fn token_as_first_argument() {
+        let acl = with_token("s3cret");
+        let mut m = msg("/file/door/start", vec![OscType::String("s3cret".into()), OscType::Int(1)]);
+        assert!(acl.check_token(&mut m));
+        assert_eq!(m.addr, "/file/door/start");
+        assert_eq!(m.args, Some(vec![OscType::Int(1)]));
+        let mut m = msg("/file/door/start", vec![OscType::String("wrong!".into())]);
+        assert!(!acl.check_token(&mut m));
+        let mut m = msg("/file/door/start", vec![OscType::Int(1)]);
+        assert!(!acl.check_token(&mut m));
+        let mut m = OscMessage { addr: "/file/door/start".into(), args: None };
+        assert!(!acl.check_token(&mut m));
+    }
+    #[test]
+    fn token_as_address_prefix() {
+        let acl = with_token("s3cret");
+        let mut m = msg("/s3cret/file/door/start", vec![OscType::String("s3cret".into())]);
+        assert!(acl.check_token(&mut m));
+        assert_eq!(m.addr, "/file/door/start");
+        // Only one copy of the token is taken off.
+        assert_eq!(m.args, Some(vec![OscType::String("s3cret".into())]));
+        for addr in ["/s3cre/file/door/start", "/s3cretx/file", "/s3cret", "/x3cret/file", "s3cret/file"].iter() {
+            let mut m = msg(addr, vec![]);
+            assert!(!acl.check_token(&mut m), "{}", addr);
+            assert_eq!(m.addr, *addr);
+        }
+        // Multi-byte characters near where the token would end don't upset it.
+        assert!(!acl.check_token(&mut msg("/s3cré/x", vec![])));
+    }
+    #[test]
+    fn no_token_needed() {
+        let acl = AccessControl::unrestricted();
+        let mut m = msg("/file/door/start", vec![OscType::String("s3cret".into())]);
+        assert!(acl.check_token(&mut m));
+        assert_eq!(m.args, Some(vec![OscType::String("s3cret".into())]));
+        assert!(acl.check_bearer(None));
+    }
+    #[test]
+    fn bearer() {
+        let acl = with_token("s3cret");
+        assert!(acl.check_bearer(Some("Bearer s3cret")));
+        assert!(acl.check_bearer(Some(" Bearer s3cret ")));
+        assert!(!acl.check_bearer(Some("Bearer s3cre")));
+        assert!(!acl.check_bearer(Some("Basic s3cret")));
+        assert!(!acl.check_bearer(Some("s3cret")));
+        assert!(!acl.check_bearer(None));
+    }
+    #[test]
+    fn compare() {
+        assert!(tokens_match(b"abc", b"abc"));
+        assert!(!tokens_match(b"abc", b"abd"));
+        assert!(!tokens_match(b"abc", b"abcd"));
+        assert!(tokens_match(b"", b""));
+    }
+}
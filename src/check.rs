@@ -0,0 +1,88 @@
+//! Checking media files off the `Context` thread.
+//!
+//! Opening every file in the config (and library) can take a while, and the
+//! `Context` thread has to keep feeding buffering threads in the meantime. So
+//! when the config is reloaded, or a file is defined, the checking happens on a
+//! thread of its own, which sends the results back as `Message::Checked` for the
+//! `Context` to apply.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Sender};
+use config::{Config, DefinedFile};
+use ctxt::{self, Message, FileInfo};
+use cli::Options;
+use osc::Replier;
+use defined;
+use failure::Error;
+
+/// Something to check.
+pub enum Job {
+    /// Load the config file, add the given defined files to it, and check
+    /// every file in it.
+    Reload(Options, HashMap<String, DefinedFile>),
+    /// Check a file that's being defined, against the current sample rate and
+    /// number of channels.
+    Define {
+        name: String,
+        def: DefinedFile,
+        sample_rate: u64,
+        channels: usize
+    }
+}
+/// A reloaded config, and what's been found out about its files.
+pub type Reloaded = (Config, HashMap<String, FileInfo>);
+/// The result of a `Job`.
+pub enum Checked {
+    /// Boxed, since a `Config` is rather big.
+    Reload(Box<Result<Reloaded, Error>>),
+    Define(String, DefinedFile, Result<FileInfo, Error>)
+}
+/// A `Job`, and who wants to know how it went.
+pub struct Request {
+    pub job: Job,
+    pub reply: Replier,
+    /// Whether to send `/ack` to `reply` once the result's been applied (if the
+    /// original message was wrapped in `WithResult`).
+    pub ack: bool
+}
+fn reload(mctx: &mut ::sqa_ffmpeg::MediaContext, opts: &Options, defined: &HashMap<String, DefinedFile>) -> Result<Reloaded, Error> {
+    info!("Reloading configuration");
+    let mut cfg = opts.load_config()
+        .map_err(|e| format_err!("failed loading config: {}", e))?;
+    defined::apply(&mut cfg, defined);
+    let (file_info, mut errors) = ctxt::check_files(mctx, &cfg);
+    if !errors.is_empty() {
+        return Err(errors.remove(0));
+    }
+    Ok((cfg, file_info))
+}
+/// Starts the checking thread, which sends its results to `tx`.
+pub fn spawn(tx: Sender<Message>) -> Sender<Request> {
+    let (rtx, rrx) = mpsc::channel::<Request>();
+    ::std::thread::spawn(move || {
+        // FFmpeg doesn't mind being initialised more than once, and this
+        // thread needs a context of its own to open files with.
+        let mut mctx = match ::sqa_ffmpeg::init() {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Failed initialising FFmpeg for checking files: {:?}", e);
+                return;
+            }
+        };
+        for req in rrx.iter() {
+            let checked = match req.job {
+                Job::Reload(opts, defined) => {
+                    Checked::Reload(Box::new(reload(&mut mctx, &opts, &defined)))
+                },
+                Job::Define { name, def, sample_rate, channels } => {
+                    let res = ctxt::check_file(&mut mctx, &name, &def.to_playback_file(), sample_rate, channels);
+                    Checked::Define(name, def, res)
+                }
+            };
+            if tx.send(Message::Checked(checked, req.reply, req.ack)).is_err() {
+                break;
+            }
+        }
+    });
+    rtx
+}
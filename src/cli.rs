@@ -0,0 +1,81 @@
+//! Command-line arguments.
+
+use config::{Config, Listen, OneOrMany, PortSpec};
+use library;
+use failure::Error;
+
+pub const USAGE: &str = "Usage: mfl-gramophone [OPTIONS]
+
+Options:
+    -c, --config PATH      config file to use (default: mfl-gramophone.toml, in
+                           the current directory)
+    -l, --listen ADDR      address to listen for OSC on, instead of the one in
+                           the config file (can be given more than once)
+        --channels PORTS   comma-separated JACK ports to send audio to, instead
+                           of the ones in the config file
+        --check            check the config file and media files, then exit
+        --list             print the configured files, then exit
+    -h, --help             print this help, then exit";
+
+/// What to do, once the config has been loaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Run,
+    Check,
+    List,
+    Help
+}
+/// Options given on the command line.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The config file (the extension is optional).
+    pub config: String,
+    pub listen: Vec<String>,
+    pub channels: Option<Vec<String>>,
+    pub mode: Mode
+}
+impl Options {
+    /// Parses `args` (not including the program name).
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let mut ret = Options {
+            config: "mfl-gramophone".into(),
+            listen: vec![],
+            channels: None,
+            mode: Mode::Run
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next()
+                .ok_or(format_err!("{} needs a value", arg));
+            match &arg as &str {
+                "-c" | "--config" => ret.config = value()?,
+                "-l" | "--listen" => ret.listen.push(value()?),
+                "--channels" => {
+                    let chans = value()?.split(',')
+                        .map(|x| x.trim().to_string())
+                        .filter(|x| !x.is_empty())
+                        .collect();
+                    ret.channels = Some(chans);
+                },
+                "--check" => ret.mode = Mode::Check,
+                "--list" => ret.mode = Mode::List,
+                "-h" | "--help" => ret.mode = Mode::Help,
+                x => bail!("unknown argument '{}'", x)
+            }
+        }
+        Ok(ret)
+    }
+    /// Loads the config file, applies any overrides given on the command line,
+    /// and scans the library directories.
+    pub fn load_config(&self) -> Result<Config, Error> {
+        let mut cfg = Config::get(&self.config)?;
+        library::scan(&mut cfg)?;
+        if !self.listen.is_empty() {
+            cfg.listen = OneOrMany::Many(self.listen.iter().map(|l| Listen::Addr(l.clone())).collect());
+        }
+        if let Some(ref chans) = self.channels {
+            cfg.channels = chans.iter().map(|c| OneOrMany::One(PortSpec::Name(c.clone()))).collect();
+        }
+        Ok(cfg)
+    }
+}
@@ -1,27 +1,446 @@
 use std::collections::HashMap;
 use std::default::Default;
+use rosc::OscType;
+use regex::Regex;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default, PartialEq)]
 pub struct PlaybackFile {
+    pub uri: String,
+    #[serde(default)]
+    pub looping: bool,
+    /// A group name, so that the file can be addressed as `@GROUP` along with others.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// The level, in decibels, to start at if a command doesn't give one.
+    #[serde(default)]
+    pub level: Option<f64>,
+    /// The MIDI Show Control cue number (e.g. "12" or "12.5") that triggers this file.
+    #[serde(default)]
+    pub cue: Option<String>,
+    /// The timecode (`HH:MM:SS:FF`) at which this file starts, when chasing timecode.
+    #[serde(default)]
+    pub timecode: Option<String>,
+    /// If set, timecode is sent out while this file plays.
+    #[serde(default)]
+    pub timecode_out: Option<TimecodeOut>,
+    /// Actions to run when this file changes state, keyed by the new state
+    /// (e.g. `started` or `finished`), or reaches a marker (`marker:NAME`).
+    #[serde(default)]
+    pub on: HashMap<String, OneOrMany<String>>,
+    #[serde(default)]
+    pub markers: Vec<Marker>,
+    /// Whether to read markers from the file's WAV `cue ` chunk, too.
+    #[serde(default)]
+    pub wav_markers: bool,
+    /// Whether this file was added at runtime (see `DefinedFile`), rather than
+    /// coming from the config file or library.
+    #[serde(skip)]
+    pub defined: bool
+}
+/// A file defined at runtime, with `/file/NAME/define`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DefinedFile {
     pub uri: String,
     #[serde(default)]
     pub looping: bool
 }
+impl DefinedFile {
+    pub fn to_playback_file(&self) -> PlaybackFile {
+        PlaybackFile {
+            uri: self.uri.clone(),
+            looping: self.looping,
+            defined: true,
+            ..Default::default()
+        }
+    }
+}
+/// A directory whose media files are registered automatically.
+#[derive(Deserialize, Clone, Debug)]
+pub struct LibraryDir {
+    pub path: String,
+    /// Which files to pick up, as an OSC-style pattern matched against file names.
+    #[serde(default = "LibraryDir::default_pattern")]
+    pub pattern: String,
+    /// Whether to look in subdirectories, too.
+    #[serde(default)]
+    pub recursive: bool,
+    /// Prepended to the name of every file found.
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Defaults for every file found, as for `[files]`.
+    #[serde(default)]
+    pub looping: bool,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub level: Option<f64>
+}
+impl LibraryDir {
+    fn default_pattern() -> String {
+        "*.{wav,flac,mp3,ogg,opus,m4a,aif,aiff}".into()
+    }
+}
+/// A named point in a file.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Marker {
+    pub name: String,
+    /// Where the marker is, in milliseconds from the start of the file.
+    pub at_ms: u64
+}
+/// How OSC packets are delimited when sent over a stream (e.g. TCP).
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// SLIP framing, as specified by OSC 1.1.
+    #[default]
+    Slip,
+    /// Each packet is prefixed by its length, as a 32-bit big-endian integer (OSC 1.0).
+    Length
+}
+/// An argument to an OSC command, as written in the config file.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ConfigArg {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String)
+}
+impl ConfigArg {
+    pub fn to_osc(&self) -> OscType {
+        match *self {
+            ConfigArg::Bool(b) => OscType::Bool(b),
+            ConfigArg::Int(i) => OscType::Int(i as _),
+            ConfigArg::Float(f) => OscType::Float(f as _),
+            ConfigArg::String(ref s) => OscType::String(s.clone())
+        }
+    }
+}
+/// Which kind of MIDI message a `MidiBinding` responds to.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MidiEventKind {
+    Note,
+    Cc,
+    Program
+}
+fn default_velocity_min_db() -> f64 {
+    -40.0
+}
+/// Runs an OSC command when a particular MIDI message is received.
+#[derive(Deserialize, Clone, Debug)]
+pub struct MidiBinding {
+    #[serde(rename = "type")]
+    pub kind: MidiEventKind,
+    /// MIDI channel (1-16) to respond to; if not set, any channel will do.
+    #[serde(default)]
+    pub channel: Option<u8>,
+    /// The note, controller or program number.
+    pub number: u8,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<ConfigArg>,
+    /// For notes, a command to run on note-off (and for CCs, when the value goes to 0).
+    #[serde(default)]
+    pub off_command: Option<String>,
+    #[serde(default)]
+    pub off_args: Vec<ConfigArg>,
+    /// If set, the note velocity (or CC value) is turned into a level in decibels,
+    /// which replaces the first argument.
+    #[serde(default)]
+    pub velocity_level: bool,
+    /// The level velocity 1 maps to; velocity 127 is always 0dB.
+    #[serde(default = "default_velocity_min_db")]
+    pub velocity_min_db: f64
+}
+fn default_msc_device_id() -> u8 {
+    0
+}
+/// Settings for receiving MIDI Show Control messages.
+#[derive(Deserialize, Clone, Debug)]
+pub struct MscConfig {
+    /// Our device ID (0-111); messages sent to other devices are ignored, apart
+    /// from ones sent to the "all call" ID (127).
+    #[serde(default = "default_msc_device_id")]
+    pub device_id: u8,
+    /// Groups (112-126) we're a member of.
+    #[serde(default)]
+    pub groups: Vec<u8>,
+    /// Command formats to respond to (e.g. 16 for "sound, general"); if empty,
+    /// any will do.
+    #[serde(default)]
+    pub command_formats: Vec<u8>,
+    /// The level, in decibels, that files are started at.
+    #[serde(default)]
+    pub level: f64
+}
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct MidiConfig {
+    /// JACK MIDI ports to connect our input to.
+    #[serde(default)]
+    pub connect: Vec<String>,
+    #[serde(default)]
+    pub bindings: Vec<MidiBinding>,
+    /// If set, MIDI Show Control messages are used to trigger files with cue numbers.
+    #[serde(default)]
+    pub msc: Option<MscConfig>
+}
+/// An OSC message sent by an action.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ActionMessage {
+    /// The name of the target (in `[targets]`) to send to.
+    pub target: String,
+    pub addr: String,
+    #[serde(default)]
+    pub args: Vec<ConfigArg>
+}
+/// A timecode frame rate.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum FrameRate {
+    #[serde(rename = "24")]
+    Fps24,
+    #[serde(rename = "25")]
+    #[default]
+    Fps25,
+    /// 29.97fps drop-frame.
+    #[serde(rename = "29.97df")]
+    Fps30Drop,
+    #[serde(rename = "30")]
+    Fps30
+}
+impl FrameRate {
+    /// The nominal number of frames per second (30 for drop-frame).
+    pub fn fps(&self) -> u64 {
+        match *self {
+            FrameRate::Fps24 => 24,
+            FrameRate::Fps25 => 25,
+            FrameRate::Fps30Drop | FrameRate::Fps30 => 30
+        }
+    }
+    pub fn is_drop_frame(&self) -> bool {
+        *self == FrameRate::Fps30Drop
+    }
+}
+/// A kind of timecode.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimecodeFormat {
+    /// MIDI timecode, over JACK MIDI.
+    Mtc,
+    /// Linear timecode, as JACK audio.
+    Ltc
+}
+fn default_tolerance_ms() -> u64 {
+    100
+}
+fn default_freewheel_ms() -> u64 {
+    500
+}
+/// Settings for chasing external timecode.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TimecodeConfig {
+    pub source: TimecodeFormat,
+    /// JACK ports to connect our input to.
+    #[serde(default)]
+    pub connect: Vec<String>,
+    /// The frame rate of LTC, and of file trigger points. (MTC says what its frame
+    /// rate is.)
+    #[serde(default)]
+    pub frame_rate: FrameRate,
+    /// How far a file can drift from the timecode before it gets relocated.
+    #[serde(default = "default_tolerance_ms")]
+    pub tolerance_ms: u64,
+    /// How long timecode can drop out for before files get stopped.
+    #[serde(default = "default_freewheel_ms")]
+    pub freewheel_ms: u64,
+    /// The level, in decibels, that files are started at.
+    #[serde(default)]
+    pub level: f64
+}
+/// Timecode to send out while a particular file plays.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct TimecodeOut {
+    pub format: TimecodeFormat,
+    #[serde(default)]
+    pub frame_rate: FrameRate,
+    /// The timecode at the start of the file (`HH:MM:SS:FF`); defaults to zero.
+    #[serde(default)]
+    pub start: Option<String>
+}
+fn default_ltc_level() -> f64 {
+    -18.0
+}
+/// Settings for sending timecode.
+#[derive(Deserialize, Clone, Debug)]
+pub struct TimecodeOutConfig {
+    /// JACK MIDI ports to send MTC to.
+    #[serde(default)]
+    pub mtc_connect: Vec<String>,
+    /// JACK audio ports to send LTC to.
+    #[serde(default)]
+    pub ltc_connect: Vec<String>,
+    /// The level of the LTC signal, in decibels.
+    #[serde(default = "default_ltc_level")]
+    pub ltc_level: f64
+}
+/// What kind of Unix domain socket to listen on.
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UnixKind {
+    /// A stream socket, carrying SLIP-framed OSC.
+    #[default]
+    Stream,
+    /// A datagram socket, carrying one OSC packet per datagram.
+    Datagram,
+    /// A stream socket, carrying one text command per line.
+    Text
+}
+/// An address to listen for OSC over UDP on.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Listen {
+    /// Just an address, like `127.0.0.1:9091` or `[::1]:9091`.
+    Addr(String),
+    /// An address, plus some multicast groups to join.
+    Multicast {
+        addr: String,
+        /// Multicast groups, like `239.0.0.1` or `ff02::1234`.
+        groups: Vec<String>,
+        /// The interface to join the groups on: an IPv4 address for IPv4 groups,
+        /// or an interface index for IPv6 ones.
+        #[serde(default)]
+        interface: Option<String>
+    }
+}
+impl Listen {
+    pub fn addr(&self) -> &str {
+        match *self {
+            Listen::Addr(ref a) => a,
+            Listen::Multicast { ref addr, .. } => addr
+        }
+    }
+}
+/// Either one thing, or a list of things.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>)
+}
+impl<T: Clone> OneOrMany<T> {
+    pub fn to_vec(&self) -> Vec<T> {
+        match *self {
+            OneOrMany::One(ref t) => vec![t.clone()],
+            OneOrMany::Many(ref v) => v.clone()
+        }
+    }
+}
+/// A JACK port (or ports) to connect an output channel to.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum PortSpec {
+    /// The full name of a port, like `system:playback_1`.
+    Name(String),
+    /// A regular expression, matched against the names of every audio input port
+    /// (apart from our own).
+    Regex {
+        regex: String
+    }
+}
+impl OneOrMany<PortSpec> {
+    /// Describes the ports, like `system:playback_1, /^rec:in_1$/`.
+    pub fn describe(&self) -> String {
+        self.to_vec().iter()
+            .map(|p| match *p {
+                PortSpec::Name(ref n) => n.clone(),
+                PortSpec::Regex { ref regex } => format!("/{}/", regex)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
 #[derive(Deserialize)]
 pub struct Config {
+    #[serde(default)]
     pub files: HashMap<String, PlaybackFile>,
-    pub listen: String,
-    pub channels: Vec<String>,
+    /// Directories to scan for more files.
+    #[serde(default)]
+    pub library: Vec<LibraryDir>,
+    /// If set, a JSON file that files defined at runtime are saved to (and
+    /// loaded from, at startup).
+    #[serde(default)]
+    pub defined_files: Option<String>,
+    pub listen: OneOrMany<Listen>,
+    #[serde(default)]
+    pub tcp_listen: Option<String>,
+    #[serde(default)]
+    pub tcp_framing: Framing,
+    #[serde(default)]
+    pub http_listen: Option<String>,
+    #[serde(default)]
+    pub oscquery_listen: Option<String>,
+    /// Path to a Unix domain socket to listen on.
+    #[serde(default)]
+    pub unix_listen: Option<String>,
+    #[serde(default)]
+    pub unix_kind: UnixKind,
+    /// Permissions for the Unix domain socket, in octal (e.g. "660").
+    #[serde(default)]
+    pub unix_mode: Option<String>,
+    /// Source addresses or CIDR ranges allowed to send commands; empty allows anyone.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// If set, a shared secret that has to accompany every command.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// If set, where to send UDP replies, instead of back to the sender.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// Where to send UDP replies to commands from particular source addresses.
+    #[serde(default)]
+    pub reply_routes: HashMap<String, String>,
+    /// If set, a JACK MIDI input is created, and used to trigger commands.
+    #[serde(default)]
+    pub midi: Option<MidiConfig>,
+    /// If set, files with trigger points follow this external timecode.
+    #[serde(default)]
+    pub timecode: Option<TimecodeConfig>,
+    /// Where to send timecode for files with `timecode_out` set.
+    #[serde(default)]
+    pub timecode_out: Option<TimecodeOutConfig>,
+    /// Devices that actions can send OSC to, as `host:port`.
+    #[serde(default)]
+    pub targets: HashMap<String, String>,
+    /// Named actions, each of which sends one or more OSC messages.
+    #[serde(default)]
+    pub actions: HashMap<String, OneOrMany<ActionMessage>>,
+    /// The port (or ports) each output channel is connected to.
+    pub channels: Vec<OneOrMany<PortSpec>>,
     pub shutdown_secs: u64,
     pub sample_rate: u64
 }
 impl Config {
-    pub fn get() -> Result<Self, ::failure::Error> {
+    /// Loads the config file `name` (with or without its extension), and applies
+    /// any `GRAMOPHONE_*` environment variables.
+    pub fn get(name: &str) -> Result<Self, ::failure::Error> {
         let mut settings = ::cfg::Config::default();
         settings
-            .merge(::cfg::File::with_name("mfl-gramophone"))?
+            .merge(::cfg::File::with_name(name))?
             .merge(::cfg::Environment::with_prefix("GRAMOPHONE"))?;
         let ret: Self = settings.try_into()?;
+        ret.validate()?;
         Ok(ret)
     }
+    /// Checks the things serde can't, like whether regular expressions compile.
+    pub fn validate(&self) -> Result<(), ::failure::Error> {
+        for (i, specs) in self.channels.iter().enumerate() {
+            for spec in specs.to_vec() {
+                if let PortSpec::Regex { ref regex } = spec {
+                    Regex::new(regex)
+                        .map_err(|e| format_err!("channel {}: invalid port regex /{}/: {}", i, regex, e))?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
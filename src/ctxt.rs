@@ -1,21 +1,95 @@
 use std::sync::mpsc::{Sender, Receiver, channel};
-use sqa_engine::{EngineContext, PlainSender};
+use sqa_engine::PlainSender;
 use sqa_ffmpeg::{MediaContext, MediaFile};
 use sqa_engine::param::{Parameter, FadeDetails};
-use config::Config;
+use config::{Config, PlaybackFile, DefinedFile, OneOrMany, PortSpec};
+use osc::{self, Replier, ReplyRoutes};
+use pattern::{self, Pattern};
+use auth::AccessControl;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use oscquery;
+use rosc::{OscMessage, OscType};
 use std::collections::HashMap;
+use std::fmt;
 use sqa_engine::sync::AudioThreadMessage;
 use failure::Error;
+use timecode::{self, Chase, TimecodeInput, TimecodeOutput};
+use actions::Actions;
+use cli::Options;
+use markers;
+use defined;
+use midi::{self, MidiInput, MidiTables};
+use engine::{self, Engine};
+use check::{self, Job};
+use std::time::{Duration, Instant};
+use std::thread::JoinHandle;
+
+/// A command failing in a way that says what kind of failure it was, so that
+/// the HTTP API can give back a sensible status code.
+#[derive(Debug)]
+pub enum CommandError {
+    /// Something the command referred to isn't there.
+    NotFound(String),
+    /// The command doesn't make sense.
+    BadRequest(String),
+    /// The command can't be carried out right now (e.g. JACK isn't running).
+    Unavailable(String)
+}
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::NotFound(ref s) | CommandError::BadRequest(ref s) | CommandError::Unavailable(ref s) => write!(f, "{}", s)
+        }
+    }
+}
+impl ::std::error::Error for CommandError {}
+impl CommandError {
+    /// Describes what kind of error `err` is: `not_found`, `bad_request`,
+    /// `unavailable`, or just `error`.
+    pub fn kind(err: &Error) -> &'static str {
+        match err.downcast_ref::<CommandError>() {
+            Some(&CommandError::NotFound(_)) => "not_found",
+            Some(&CommandError::BadRequest(_)) => "bad_request",
+            Some(&CommandError::Unavailable(_)) => "unavailable",
+            None => "error"
+        }
+    }
+}
+/// Replies to `reply` with `/error` for `err`. If `with_kind` is set (i.e. the
+/// command was wrapped in `WithResult`), the kind of error (see
+/// `CommandError::kind`) is sent as a second argument.
+fn send_error(reply: &Replier, err: &Error, with_kind: bool) {
+    let mut args = vec![OscType::String(err.to_string())];
+    if with_kind {
+        args.push(OscType::String(CommandError::kind(err).into()));
+    }
+    reply.send(OscMessage {
+        addr: "/error".into(),
+        args: Some(args)
+    });
+}
 
 pub enum Message {
+    /// /shutdown
+    ///
+    /// Fades everything out over `shutdown_secs`, then stops. Sending it again
+    /// stops straight away.
     Shutdown,
     Ping,
-    /// /file/NAME/play LEVEL
+    /// /file/NAME/play [LEVEL]
     ///
     /// Starts playing a file.
     ///
-    /// - LEVEL: the volume level, in decibels, to begin playback at
-    PlayFile(String, f64),
+    /// - LEVEL: the volume level, in decibels, to begin playback at (if not
+    ///   given, the file's configured level)
+    PlayFile(String, Option<f64>),
+    /// /file/NAME/load [LEVEL]
+    ///
+    /// Loads a file, ready to be started later, without playing it.
+    ///
+    /// - LEVEL: as for PlayFile
+    LoadFile(String, Option<f64>),
     /// /file/NAME/fade LEVEL DURATION
     ///
     /// Fades the volume of a file.
@@ -27,13 +101,140 @@ pub enum Message {
     /// 
     /// Stops playback.
     StopFile(String),
+    /// /file/NAME/define URI [LOOPING]
+    ///
+    /// Adds a file (or changes one added this way), checking it first, and
+    /// replies with `/reply/define NAME CHANNELS DURATION_MS`.
+    DefineFile(String, DefinedFile, Replier),
+    /// /file/NAME/undefine
+    ///
+    /// Removes a file added with DefineFile.
+    UndefineFile(String),
     /// /file/NAME/debug
     ///
-    /// Prints debug information to the logs.
-    DebugFile(String),
+    /// Prints debug information to the logs, and replies with it.
+    DebugFile(String, Replier),
+    /// /debug
+    ///
+    /// Like DebugFile, but for the whole engine.
+    Debug(Replier),
+    /// /query/WHAT
+    ///
+    /// Replies with information about the server's state.
+    Query(Query, Replier),
+    /// /subscribe
+    ///
+    /// Starts sending `/event` messages about state changes to the sender.
+    Subscribe(Replier),
+    /// /unsubscribe
+    ///
+    /// Stops sending `/event` messages to the sender.
+    Unsubscribe(Replier),
+    /// /send/ACTION [ARGS...]
+    ///
+    /// Runs an action, sending its OSC messages (with ARGS appended).
+    SendAction(String, Vec<OscType>),
+    /// /reload
+    ///
+    /// Re-reads the config file, and replies with what changed.
+    Reload(Replier),
+    /// Sent periodically, to check on playing files.
+    Tick,
+    /// Processes the inner message, then replies with `/ack` if that succeeded,
+    /// or `/error MESSAGE` if it didn't.
+    WithResult(Box<Message>, Replier),
+    /// The position of the timecode being chased, in milliseconds, or `None` if
+    /// it's stopped.
+    Timecode(Option<u64>),
+    /// A JACK port appeared or went away.
+    PortsChanged,
+    /// /channel/N/patch PORT [PORT...]
+    ///
+    /// Connects output channel N to the given ports, instead of its configured ones.
+    PatchChannel(usize, Vec<String>),
+    /// A reload or definition that's been checked (see `check`), ready to be
+    /// applied; its replies go to the `Replier`, followed by `/ack` if the bool
+    /// is set.
+    Checked(check::Checked, Replier, bool),
     Engine(AudioThreadMessage),
     BufferComplete(String, u32)
 }
+impl Message {
+    /// Whether handling this message needs the audio engine to be running.
+    pub fn needs_engine(&self) -> bool {
+        use self::Message::*;
+
+        match *self {
+            PlayFile(..) | LoadFile(..) | FadeFile(..) | StopFile(..) | DebugFile(..) | Debug(..) |
+                PatchChannel(..) => true,
+            WithResult(ref msg, _) => msg.needs_engine(),
+            _ => false
+        }
+    }
+}
+/// How often, in milliseconds, `Message::Tick` gets sent.
+pub const TICK_MS: u64 = 10;
+/// How long to wait between attempts to start the audio engine, if JACK isn't running.
+pub const RECONNECT_SECS: u64 = 2;
+/// Something that can be asked about with `/query`.
+pub enum Query {
+    /// /query/files: every configured file.
+    Files,
+    /// /query/active: every file that's currently loaded or playing.
+    Active,
+    /// /query/status: the state of the audio engine.
+    Status,
+    /// /query/namespace: the OSCQuery namespace, as a JSON string.
+    Namespace
+}
+/// Information about a media file, gathered when it gets checked.
+pub struct FileInfo {
+    pub channels: usize,
+    pub sample_rate: u64,
+    pub duration_ms: u64,
+    /// The file's markers, as (name, position in milliseconds), sorted by position.
+    pub markers: Vec<(String, u64)>
+}
+/// Opens the file `pf`, and checks whether it can be played at `sample_rate`
+/// with `channels` output channels.
+pub fn check_file(mctx: &mut MediaContext, name: &str, pf: &PlaybackFile, sample_rate: u64, channels: usize) -> Result<FileInfo, Error> {
+    let mf = MediaFile::new(mctx, &pf.uri)
+        .map_err(|e| format_err!("failed opening file '{}': {}", name, e))?;
+    if mf.sample_rate() as u64 != sample_rate {
+        bail!("File '{}' has sample rate {} (needed {})", name, mf.sample_rate(), sample_rate);
+    }
+    if mf.channels() == 0 {
+        bail!("File '{}' has no channels", name);
+    }
+    if mf.channels() > channels {
+        warn!("File '{}' has more channels ({}) than configured ({}); some will not play!", name, mf.channels(), channels);
+    }
+    Ok(FileInfo {
+        channels: mf.channels(),
+        sample_rate: mf.sample_rate() as _,
+        duration_ms: mf.duration().num_milliseconds() as _,
+        markers: markers::file_markers(name, pf)?
+    })
+}
+/// Checks every file in `cfg`, returning information about the ones that are
+/// fine, and errors for the ones that aren't.
+pub fn check_files(mctx: &mut MediaContext, cfg: &Config) -> (HashMap<String, FileInfo>, Vec<Error>) {
+    let mut names = cfg.files.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut info = HashMap::new();
+    let mut errors = vec![];
+    for name in names {
+        let pf = &cfg.files[name];
+        info!("[+] Checking '{}' ({})...", name, pf.uri);
+        match check_file(mctx, name, pf, cfg.sample_rate, cfg.channels.len()) {
+            Ok(fi) => {
+                info.insert(name.clone(), fi);
+            },
+            Err(e) => errors.push(e)
+        }
+    }
+    (info, errors)
+}
 /// Converts a linear amplitude to decibels.
 pub fn lin_db(lin: f64) -> f64 {
     lin.log10() * 20.0
@@ -51,7 +252,34 @@ pub struct ActiveFile {
     senders: Vec<PlainSender>,
     buffered: bool,
     epoch: u32,
-    tx: Sender<BufferingMessage>
+    /// Where in the file playback started, in milliseconds.
+    offset_ms: u64,
+    /// How many times the file has looped.
+    loops: u64,
+    /// The position (within the current loop) from which markers haven't been
+    /// checked yet, or `None` if they haven't been checked at all.
+    marker_from: Option<u64>,
+    tx: Sender<BufferingMessage>,
+    /// The buffering thread, so that it can be waited for on shutdown.
+    thread: Option<JoinHandle<()>>
+}
+impl ActiveFile {
+    /// The file's current volume, in decibels.
+    pub fn level(&self) -> f64 {
+        let time = PlainSender::precise_time_ns();
+        lin_db(self.senders[0].volume().get(time) as f64)
+    }
+    /// The file's playback position, in milliseconds.
+    pub fn position_ms(&self, sample_rate: u64) -> u64 {
+        self.offset_ms + self.senders[0].position_samples() * 1000 / sample_rate
+    }
+    /// A short description of the file's state (`playing` or `loaded`).
+    pub fn state(&self) -> &'static str {
+        if self.senders[0].active() { "playing" } else { "loaded" }
+    }
+    pub fn buffered(&self) -> bool {
+        self.buffered
+    }
 }
 impl Drop for ActiveFile {
     fn drop(&mut self) {
@@ -62,37 +290,172 @@ impl Drop for ActiveFile {
 pub struct Context {
     pub tx: Sender<Message>,
     pub rx: Receiver<Message>,
-    pub ec: EngineContext,
+    /// The audio engine, or `None` if JACK isn't running.
+    pub engine: Option<Engine>,
+    /// Whether `engine` is there, shared with the things that take commands, so
+    /// that they can reject ones that need it.
+    pub jack_up: Arc<AtomicBool>,
     pub mctx: MediaContext,
     pub active_files: HashMap<String, ActiveFile>,
+    pub file_info: HashMap<String, FileInfo>,
     pub cfg: Config,
-    pub epoch: u32
+    pub epoch: u32,
+    pub xruns: u64,
+    pub subscribers: Vec<Replier>,
+    pub acl: Arc<AccessControl>,
+    /// Where UDP replies go, shared with the OSC listeners.
+    pub routes: Arc<Mutex<ReplyRoutes>>,
+    /// Timecode chasing state, if it's enabled.
+    pub chase: Option<Chase>,
+    /// The MIDI input, if it's configured and JACK is running.
+    pub midi_in: Option<MidiInput>,
+    /// The timecode input, if chasing is enabled and JACK is running.
+    pub timecode_in: Option<TimecodeInput>,
+    /// Where to send timecode, if any files have timecode output.
+    pub timecode_out: Option<TimecodeOutput>,
+    pub actions: Actions,
+    /// Command-line options, used to reload the config.
+    pub opts: Options,
+    /// Files defined at runtime, which get added back in on reload.
+    pub defined: HashMap<String, DefinedFile>,
+    /// Ports channels were patched to with `/channel/N/patch`, which get put
+    /// back on reload.
+    pub patches: HashMap<usize, Vec<String>>,
+    /// What MIDI input does, shared with the MIDI input's worker thread.
+    pub midi: Arc<RwLock<MidiTables>>,
+    /// Where to send files to be checked (see `check`).
+    pub checker: Sender<check::Request>,
+    /// If we're shutting down, when to stop.
+    pub shutdown_at: Option<Instant>,
+    /// If the audio engine isn't running, when to next try to start it
+    /// (`None` means straight away).
+    pub reconnect_at: Option<Instant>,
+    /// Why the last attempt to start the audio engine failed, so that the same
+    /// error isn't logged every time.
+    pub reconnect_error: Option<String>
 }
 impl Context {
     pub fn process_message(&mut self, msg: Message) -> Result<(), Error> {
         use self::Message::*;
 
+        if self.engine.is_none() && msg.needs_engine() {
+            let err: Error = CommandError::Unavailable("JACK isn't running; waiting for it to come back.".into()).into();
+            if let WithResult(_, reply) = msg {
+                send_error(&reply, &err, true);
+            }
+            return Err(err);
+        }
         match msg {
             Shutdown => self.shutdown(),
             Ping => info!("Ping received"),
             PlayFile(st, level) => {
-                self.prepare_file(&st, level)?;
-                self.start_stop_file(&st, true)?;
+                self.for_each_file(&st, false, |ctx, name| {
+                    let level = ctx.file_level(name, level);
+                    let loaded = ctx.active_files.get(name)
+                        .map(|f| !f.senders[0].active())
+                        .unwrap_or(false);
+                    if loaded {
+                        ctx.set_file_level(name, level)?;
+                    }
+                    else {
+                        ctx.prepare_file(name, level)?;
+                    }
+                    ctx.start_stop_file(name, true)?;
+                    ctx.notify_file(name, "started");
+                    Ok(())
+                })?;
+            },
+            LoadFile(st, level) => {
+                self.for_each_file(&st, false, |ctx, name| {
+                    let level = ctx.file_level(name, level);
+                    ctx.prepare_file(name, level)?;
+                    ctx.notify_file(name, "loaded");
+                    Ok(())
+                })?;
             },
             StopFile(st) => {
-                self.start_stop_file(&st, false)?;
+                self.for_each_file(&st, true, |ctx, name| {
+                    ctx.start_stop_file(name, false)?;
+                    ctx.notify_file(name, "stopped");
+                    Ok(())
+                })?;
+            },
+            DefineFile(name, def, reply) => {
+                self.start_define(name, def, reply, false)?;
             },
-            DebugFile(st) => {
-                self.debug_file(&st)?;
+            UndefineFile(name) => {
+                self.undefine_file(&name)?;
+            },
+            DebugFile(st, reply) => {
+                self.for_each_file(&st, true, |ctx, name| {
+                    for msg in ctx.debug_file(name)? {
+                        reply.send(msg);
+                    }
+                    Ok(())
+                })?;
             },
             FadeFile(st, target, dur_ms) => {
-                self.configure_file_fade(&st, target, dur_ms)?;
+                self.for_each_file(&st, true, |ctx, name| {
+                    ctx.configure_file_fade(name, target, dur_ms)?;
+                    ctx.notify_file(name, "fading");
+                    Ok(())
+                })?;
+            },
+            Subscribe(reply) => {
+                let desc = reply.describe();
+                if self.subscribers.iter().any(|s| s.describe() == desc) {
+                    info!("{} is already subscribed", desc);
+                }
+                else {
+                    info!("Subscribing {} to events", desc);
+                    self.subscribers.push(reply);
+                }
+            },
+            Unsubscribe(reply) => {
+                let desc = reply.describe();
+                info!("Unsubscribing {} from events", desc);
+                self.subscribers.retain(|s| s.describe() != desc);
+            },
+            Query(q, reply) => {
+                for msg in self.query(q) {
+                    if !reply.fits(&msg) {
+                        reply.send(OscMessage {
+                            addr: "/error".into(),
+                            args: Some(vec![OscType::String(format!("The reply ({}) is too big to fit in a datagram; ask over a stream connection instead.", msg.addr))])
+                        });
+                        continue;
+                    }
+                    reply.send(msg);
+                }
+            },
+            Debug(reply) => {
+                for msg in self.debug_engine() {
+                    reply.send(msg);
+                }
+            },
+            WithResult(msg, reply) => {
+                match *msg {
+                    // These send `/ack` themselves, once they've been checked.
+                    Reload(reply) => self.start_reload(reply, true)?,
+                    DefineFile(name, def, reply) => self.start_define(name, def, reply, true)?,
+                    msg => match self.process_message(msg) {
+                        Ok(_) => reply.send(OscMessage {
+                            addr: "/ack".into(),
+                            args: None
+                        }),
+                        Err(e) => {
+                            send_error(&reply, &e, true);
+                            return Err(e);
+                        }
+                    }
+                }
             },
             Engine(msg) => {
                 use self::AudioThreadMessage::*;
 
                 match msg {
                     Xrun => {
+                        self.xruns += 1;
                         warn!("Audio thread xrun!");
                     },
                     PlayerInvalidOutpatch(uu) => {
@@ -115,6 +478,10 @@ impl Context {
                             if self.active_files[&n].buffered {
                                 info!("File '{}' finished playback", n);
                                 self.active_files.remove(&n);
+                                if let Some(ref mut tc) = self.timecode_out {
+                                    tc.stop_file(&n);
+                                }
+                                self.notify_file(&n, "finished");
                             }
                             else {
                                 warn!("File '{}' ran out of samples!", n);
@@ -127,6 +494,68 @@ impl Context {
                     _ => {}
                 }
             },
+            SendAction(pat, args) => {
+                let names = self.actions.resolve(&pat);
+                if names.is_empty() {
+                    info!("Pattern '{}' didn't match any actions", pat);
+                }
+                for name in names {
+                    self.actions.fire(&name, &args)?;
+                }
+            },
+            Reload(reply) => {
+                self.start_reload(reply, false)?;
+            },
+            Checked(checked, reply, ack) => {
+                let res = match checked {
+                    check::Checked::Reload(res) => {
+                        (*res).and_then(|(cfg, file_info)| self.reload(cfg, file_info))
+                    },
+                    check::Checked::Define(name, def, res) => {
+                        res.and_then(|info| self.define_file(&name, def, info))
+                            .map(|msg| vec![msg])
+                    }
+                };
+                match res {
+                    Ok(msgs) => {
+                        for msg in msgs {
+                            reply.send(msg);
+                        }
+                        if ack {
+                            reply.send(OscMessage {
+                                addr: "/ack".into(),
+                                args: None
+                            });
+                        }
+                    },
+                    Err(e) => {
+                        send_error(&reply, &e, ack);
+                        return Err(e);
+                    }
+                }
+            },
+            Tick => self.tick(),
+            Timecode(pos) => {
+                self.chase_timecode(pos);
+            },
+            PortsChanged => {
+                self.repatch();
+            },
+            PatchChannel(idx, ports) => {
+                if idx >= self.cfg.channels.len() {
+                    return Err(CommandError::NotFound(format!("There's no channel {}.", idx)).into());
+                }
+                let specs = ports.iter().cloned().map(PortSpec::Name).collect::<Vec<_>>();
+                let changes = match self.engine {
+                    Some(ref mut e) => e.patch(idx, &specs)?,
+                    None => vec![]
+                };
+                self.cfg.channels[idx] = OneOrMany::Many(specs);
+                self.patches.insert(idx, ports);
+                for (port, connected) in changes {
+                    self.notify_channel(idx, &port, connected);
+                }
+            },
             BufferComplete(st, epo) => {
                 if let Some(fi) = self.active_files.get_mut(&st) {
                     if epo == fi.epoch {
@@ -137,6 +566,388 @@ impl Context {
         }
         Ok(())
     }
+    /// Sends `msg` to everyone who's subscribed to events.
+    pub fn notify(&mut self, msg: OscMessage) {
+        self.subscribers.retain(|s| {
+            if let Err(e) = s.try_send(msg.clone()) {
+                warn!("Failed to send event to {}: {}", s.describe(), e);
+                if s.is_connection() {
+                    info!("Unsubscribing {}", s.describe());
+                    return false;
+                }
+            }
+            true
+        });
+    }
+    /// Notifies subscribers that the file `name` changed state to `state`, and
+    /// runs any actions the file has for that state.
+    pub fn notify_file(&mut self, name: &str, state: &str) {
+        self.notify(OscMessage {
+            addr: "/event/file".into(),
+            args: Some(vec![
+                OscType::String(name.into()),
+                OscType::String(state.into())
+            ])
+        });
+        let actions = self.cfg.files.get(name)
+            .and_then(|pf| pf.on.get(state))
+            .map(|a| a.to_vec())
+            .unwrap_or(vec![]);
+        for action in actions {
+            if let Err(e) = self.actions.fire(&action, &[]) {
+                warn!("Failed running action '{}' for file '{}': {}", action, name, e);
+            }
+        }
+    }
+    /// Starts reloading the config file: it gets loaded and its files checked
+    /// off this thread, and then `reload` switches over to it.
+    fn start_reload(&mut self, reply: Replier, ack: bool) -> Result<(), Error> {
+        let job = Job::Reload(self.opts.clone(), self.defined.clone());
+        self.check(job, reply, ack)
+    }
+    /// Sends `job` off to be checked; the result comes back as `Message::Checked`.
+    ///
+    /// If that can't be done, replies with `/error` straight away.
+    fn check(&mut self, job: Job, reply: Replier, ack: bool) -> Result<(), Error> {
+        let req = check::Request { job, reply, ack };
+        if let Err(e) = self.checker.send(req) {
+            let err: Error = CommandError::Unavailable("The file checking thread has stopped.".into()).into();
+            send_error(&e.0.reply, &err, ack);
+            return Err(err);
+        }
+        Ok(())
+    }
+    /// Switches over to the config `cfg` (whose files have been checked, giving
+    /// `file_info`), loaded by `start_reload`.
+    ///
+    /// Files that are already playing carry on, even if they've been changed or
+    /// removed. If anything's wrong with the new config, nothing changes.
+    /// Returns `/reply/reload NAME CHANGE` messages, saying which files were
+    /// `added`, `changed` or `removed`, followed by `/reply/reload/end COUNT`.
+    pub fn reload(&mut self, mut cfg: Config, mut file_info: HashMap<String, FileInfo>) -> Result<Vec<OscMessage>, Error> {
+        if self.cfg.timecode.is_some() != cfg.timecode.is_some() {
+            bail!("Turning timecode chasing on or off needs a restart");
+        }
+        if self.cfg.channels.len() != cfg.channels.len() || self.cfg.sample_rate != cfg.sample_rate {
+            bail!("Changing the number of channels or the sample rate needs a restart");
+        }
+        let actions = Actions::new(&cfg)?;
+        let acl = AccessControl::new(&cfg)?;
+        let routes = ReplyRoutes::new(&cfg)?;
+        let mut chase = match cfg.timecode {
+            Some(ref tc) => Some(Chase::new(tc, &cfg.files)?),
+            None => None
+        };
+        let tc_files = timecode::output_files(&cfg.files)?;
+        // Nothing can fail from here on.
+        self.merge_defined(&mut cfg, &mut file_info);
+        for (&idx, ports) in self.patches.iter() {
+            // The number of channels can't change on reload, so this is
+            // always there.
+            cfg.channels[idx] = OneOrMany::Many(ports.iter().cloned().map(PortSpec::Name).collect());
+        }
+        if let (Some(new), Some(old)) = (chase.as_mut(), self.chase.as_mut()) {
+            new.chased = ::std::mem::take(&mut old.chased);
+        }
+        if let Some(ref mut tc) = self.timecode_out {
+            tc.set_files(tc_files);
+        }
+        let mut changes = vec![];
+        for (name, pf) in cfg.files.iter() {
+            match self.cfg.files.get(name) {
+                None => changes.push((name.clone(), "added")),
+                Some(old) if old != pf => changes.push((name.clone(), "changed")),
+                _ => {}
+            }
+        }
+        for name in self.cfg.files.keys() {
+            if !cfg.files.contains_key(name) {
+                changes.push((name.clone(), "removed"));
+            }
+        }
+        changes.sort();
+        for &(ref name, change) in changes.iter() {
+            info!("File '{}' {}", name, change);
+        }
+        self.actions = actions;
+        self.chase = chase;
+        self.acl.replace(acl);
+        self.routes.lock().unwrap().replace(routes);
+        let midi_changed = self.cfg.midi.as_ref().map(|m| &m.connect) != cfg.midi.as_ref().map(|m| &m.connect);
+        *self.midi.write().unwrap() = MidiTables::new(cfg.midi.as_ref(), &cfg.files);
+        self.cfg = cfg;
+        self.file_info = file_info;
+        if midi_changed && self.engine.is_some() {
+            self.start_midi();
+        }
+        self.repatch();
+        info!("Configuration reloaded ({} files changed)", changes.len());
+        let mut ret = changes.iter()
+            .map(|&(ref name, change)| OscMessage {
+                addr: "/reply/reload".into(),
+                args: Some(vec![
+                    OscType::String(name.clone()),
+                    OscType::String(change.into())
+                ])
+            })
+            .collect::<Vec<_>>();
+        ret.push(OscMessage {
+            addr: "/reply/reload/end".into(),
+            args: Some(vec![OscType::Int(changes.len() as _)])
+        });
+        Ok(ret)
+    }
+    /// Saves `defined` to the `defined_files` file, if there is one.
+    fn save_defined(&self, defined: &HashMap<String, DefinedFile>) -> Result<(), Error> {
+        match self.cfg.defined_files {
+            Some(ref path) => defined::save(path, defined),
+            None => Ok(())
+        }
+    }
+    /// Files can be defined or undefined while a reload is being checked, so
+    /// this makes the runtime-defined files in `cfg` match the ones we've got now.
+    fn merge_defined(&mut self, cfg: &mut Config, file_info: &mut HashMap<String, FileInfo>) {
+        let stale = cfg.files.iter()
+            .filter(|&(name, pf)| pf.defined && !self.defined.contains_key(name))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in stale {
+            cfg.files.remove(&name);
+            file_info.remove(&name);
+        }
+        for (name, def) in self.defined.iter() {
+            if cfg.files.get(name).map(|pf| !pf.defined).unwrap_or(false) {
+                // Shadowed by a file in the config file.
+                continue;
+            }
+            if !self.cfg.files.get(name).map(|pf| pf.defined).unwrap_or(false) {
+                // Not in use before, so what was checked is up to date.
+                continue;
+            }
+            if let Some(info) = self.file_info.remove(name) {
+                cfg.files.insert(name.clone(), def.to_playback_file());
+                file_info.insert(name.clone(), info);
+            }
+        }
+    }
+    /// Starts defining the file `name`: it gets checked off this thread, and
+    /// then `define_file` adds it.
+    fn start_define(&mut self, name: String, def: DefinedFile, reply: Replier, ack: bool) -> Result<(), Error> {
+        if let Err(e) = self.can_define(&name) {
+            reply.send(OscMessage {
+                addr: "/error".into(),
+                args: Some(vec![OscType::String(e.to_string())])
+            });
+            return Err(e);
+        }
+        let job = Job::Define {
+            name, def,
+            sample_rate: self.cfg.sample_rate,
+            channels: self.cfg.channels.len()
+        };
+        self.check(job, reply, ack)
+    }
+    /// Checks whether `name` can be defined (or redefined) at runtime.
+    fn can_define(&self, name: &str) -> Result<(), Error> {
+        if name.is_empty() || name.starts_with('@') || pattern::is_pattern(name) || name.contains(|c: char| osc::RESERVED_CHARS.contains(c)) {
+            return Err(CommandError::BadRequest(format!("'{}' can't be used as a file name", name)).into());
+        }
+        if self.cfg.files.get(name).map(|pf| !pf.defined).unwrap_or(false) {
+            return Err(CommandError::BadRequest(format!("File '{}' is in the config file, so it can't be redefined", name)).into());
+        }
+        Ok(())
+    }
+    /// Adds the file `name` (checked by `start_define`, giving `info`), or
+    /// changes it if it was defined before.
+    ///
+    /// Like reloading, if the file is already playing, it carries on.
+    pub fn define_file(&mut self, name: &str, def: DefinedFile, info: FileInfo) -> Result<OscMessage, Error> {
+        // The config might have been reloaded in the meantime.
+        self.can_define(name)?;
+        let pf = def.to_playback_file();
+        let mut defined = self.defined.clone();
+        defined.insert(name.into(), def);
+        self.save_defined(&defined)?;
+        info!("Defined file '{}' ({})", name, pf.uri);
+        let ret = OscMessage {
+            addr: "/reply/define".into(),
+            args: Some(vec![
+                OscType::String(name.into()),
+                OscType::Int(info.channels as _),
+                OscType::Int(info.duration_ms as _)
+            ])
+        };
+        self.defined = defined;
+        self.cfg.files.insert(name.into(), pf);
+        self.file_info.insert(name.into(), info);
+        self.update_cues();
+        self.notify_file(name, "defined");
+        Ok(ret)
+    }
+    /// Removes the file `name`, which has to have been added with `define_file`.
+    pub fn undefine_file(&mut self, name: &str) -> Result<(), Error> {
+        if !self.defined.contains_key(name) {
+            return Err(CommandError::NotFound(format!("File '{}' wasn't defined at runtime", name)).into());
+        }
+        let mut defined = self.defined.clone();
+        defined.remove(name);
+        self.save_defined(&defined)?;
+        info!("Undefined file '{}'", name);
+        self.notify_file(name, "undefined");
+        self.defined = defined;
+        // It might have been shadowed by a file in the config file, which should stay.
+        if self.cfg.files.get(name).map(|pf| pf.defined).unwrap_or(false) {
+            self.cfg.files.remove(name);
+            self.file_info.remove(name);
+            self.update_cues();
+        }
+        Ok(())
+    }
+    /// Tells the MIDI input which files the MSC cue numbers trigger now.
+    fn update_cues(&self) {
+        self.midi.write().unwrap().cues = MidiTables::cue_table(&self.cfg.files);
+    }
+    /// Notifies subscribers that the file `name` reached the marker `marker`
+    /// (at `at_ms`), and runs any actions the file has for it.
+    pub fn notify_marker(&mut self, name: &str, marker: &str, at_ms: u64) {
+        info!("File '{}' reached marker '{}'", name, marker);
+        self.notify(OscMessage {
+            addr: "/event/marker".into(),
+            args: Some(vec![
+                OscType::String(name.into()),
+                OscType::String(marker.into()),
+                OscType::Int(at_ms as i32)
+            ])
+        });
+        let actions = self.cfg.files.get(name)
+            .and_then(|pf| pf.on.get(&format!("marker:{}", marker)))
+            .map(|a| a.to_vec())
+            .unwrap_or(vec![]);
+        for action in actions {
+            if let Err(e) = self.actions.fire(&action, &[]) {
+                warn!("Failed running action '{}' for file '{}': {}", action, name, e);
+            }
+        }
+    }
+    /// Checks whether any playing files have looped, or passed any markers (and
+    /// whether it's time to try starting the audio engine again).
+    pub fn tick(&mut self) {
+        if self.engine.as_ref().map(|e| e.has_shut_down()).unwrap_or(false) {
+            self.jack_lost("the server shut down");
+        }
+        self.reconnect();
+        let sample_rate = self.cfg.sample_rate;
+        let mut looped = vec![];
+        let mut passed = vec![];
+        let mut positions = vec![];
+        for (name, file) in self.active_files.iter_mut() {
+            let info = match self.file_info.get(name) {
+                Some(i) if i.duration_ms > 0 => i,
+                _ => continue
+            };
+            if file.state() != "playing" {
+                continue;
+            }
+            let looping = self.cfg.files.get(name).map(|f| f.looping).unwrap_or(false);
+            let pos = file.position_ms(sample_rate);
+            let (loops, now) = if looping {
+                (pos / info.duration_ms, pos % info.duration_ms)
+            }
+            else {
+                (0, pos)
+            };
+            positions.push((name.clone(), now));
+            let from = file.marker_from.unwrap_or(file.offset_ms % info.duration_ms);
+            let mut crossed = |lo: u64, hi: u64| {
+                for &(ref marker, at) in info.markers.iter() {
+                    if at >= lo && at < hi {
+                        passed.push((name.clone(), marker.clone(), at));
+                    }
+                }
+            };
+            if loops > file.loops {
+                file.loops = loops;
+                looped.push(name.clone());
+                crossed(from, info.duration_ms);
+                crossed(0, now + 1);
+            }
+            else if now >= from {
+                crossed(from, now + 1);
+            }
+            file.marker_from = Some(now + 1);
+        }
+        if let Some(ref mut tc) = self.timecode_out {
+            for &(ref name, now) in positions.iter() {
+                tc.sync_file(name, now);
+            }
+        }
+        for name in looped {
+            self.notify_file(&name, "looped");
+        }
+        for (name, marker, at) in passed {
+            self.notify_marker(&name, &marker, at);
+        }
+    }
+    /// Works out what level to start `name` at, if `level` wasn't given.
+    pub fn file_level(&self, name: &str, level: Option<f64>) -> f64 {
+        level.or(self.cfg.files.get(name).and_then(|f| f.level))
+            .unwrap_or(0.0)
+    }
+    /// Resolves the OSC address pattern `pat` to a list of file names.
+    ///
+    /// If `pat` isn't actually a pattern, it's returned as-is, so that the caller
+    /// can complain if it doesn't exist. Otherwise, it's matched against the
+    /// configured files (only the active ones, if `active_only` is set).
+    ///
+    /// `@GROUP` selects every file in the group `GROUP` (which can also be a
+    /// pattern).
+    pub fn resolve_files(&self, pat: &str, active_only: bool) -> Vec<String> {
+        let group = pat.strip_prefix('@');
+        if group.is_none() && !pattern::is_pattern(pat) {
+            return vec![pat.into()];
+        }
+        let compiled = Pattern::new(group.unwrap_or(pat));
+        let mut ret = self.cfg.files.iter()
+            .filter(|&(name, _)| !active_only || self.active_files.contains_key(name))
+            .filter(|&(name, pf)| match group {
+                Some(_) => pf.group.as_ref().map(|x| compiled.matches(x)).unwrap_or(false),
+                None => compiled.matches(name)
+            })
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        ret.sort();
+        ret
+    }
+    /// Calls `func` for each file matching the pattern `pat` (see `resolve_files`).
+    pub fn for_each_file<F>(&mut self, pat: &str, active_only: bool, mut func: F) -> Result<(), Error>
+        where F: FnMut(&mut Self, &str) -> Result<(), Error> {
+        let names = self.resolve_files(pat, active_only);
+        if names.is_empty() {
+            info!("Pattern '{}' didn't match any files", pat);
+        }
+        let mut failed = 0;
+        let mut last_err = None;
+        for name in names.iter() {
+            if let Err(e) = func(self, name) {
+                if names.len() > 1 {
+                    warn!("Error handling file '{}': {}", name, e);
+                }
+                failed += 1;
+                last_err = Some(e);
+            }
+        }
+        match last_err {
+            Some(e) => {
+                if names.len() == 1 {
+                    Err(e)
+                }
+                else {
+                    Err(format_err!("{} of {} files matching '{}' failed", failed, names.len(), pat))
+                }
+            },
+            None => Ok(())
+        }
+    }
     pub fn lookup_uu(&mut self, uu: ::uuid::Uuid) -> Option<String> {
         let mut name = None;
         for (st, fi) in self.active_files.iter_mut() {
@@ -149,23 +960,172 @@ impl Context {
         }
         name
     }
-    pub fn debug_file(&mut self, file: &str) -> Result<(), Error> {
-        info!("Debugging state for file '{}'", file);
-        let file = self.active_files.get_mut(file)
-            .ok_or(format_err!("No such active file."))?;
+    pub fn query(&mut self, q: Query) -> Vec<OscMessage> {
+        let mut ret = vec![];
+        match q {
+            Query::Files => {
+                let mut names = self.cfg.files.keys().collect::<Vec<_>>();
+                names.sort();
+                for name in names.iter() {
+                    let pf = &self.cfg.files[*name];
+                    let (channels, duration_ms, sample_rate) = match self.file_info.get(*name) {
+                        Some(fi) => (fi.channels as i32, fi.duration_ms as i32, fi.sample_rate as i32),
+                        None => (0, 0, 0)
+                    };
+                    ret.push(OscMessage {
+                        addr: "/reply/files".into(),
+                        args: Some(vec![
+                            OscType::String(name.to_string()),
+                            OscType::String(pf.uri.clone()),
+                            OscType::Bool(pf.looping),
+                            OscType::Int(channels),
+                            OscType::Int(duration_ms),
+                            OscType::Int(sample_rate)
+                        ])
+                    });
+                }
+                ret.push(OscMessage {
+                    addr: "/reply/files/end".into(),
+                    args: Some(vec![OscType::Int(names.len() as _)])
+                });
+            },
+            Query::Active => {
+                let sample_rate = self.cfg.sample_rate;
+                let mut names = self.active_files.keys().cloned().collect::<Vec<_>>();
+                names.sort();
+                for name in names.iter() {
+                    let file = &self.active_files[name];
+                    ret.push(OscMessage {
+                        addr: "/reply/active".into(),
+                        args: Some(vec![
+                            OscType::String(name.clone()),
+                            OscType::Float(file.level() as f32),
+                            OscType::Int(file.position_ms(sample_rate) as i32),
+                            OscType::String(file.state().into()),
+                            OscType::Bool(file.buffered)
+                        ])
+                    });
+                }
+                ret.push(OscMessage {
+                    addr: "/reply/active/end".into(),
+                    args: Some(vec![OscType::Int(names.len() as _)])
+                });
+            },
+            Query::Namespace => {
+                ret.push(OscMessage {
+                    addr: "/reply/namespace".into(),
+                    args: Some(vec![OscType::String(oscquery::build_namespace(self).to_string())])
+                });
+            },
+            Query::Status => {
+                let players = self.active_files.values()
+                    .map(|f| f.senders.len())
+                    .sum::<usize>();
+                let mut args = vec![
+                    OscType::Int(self.jack_sample_rate() as _),
+                    OscType::Int(players as _),
+                    OscType::Int(self.xruns as _),
+                    OscType::Int(self.acl.rejected() as _)
+                ];
+                for ch in self.cfg.channels.iter() {
+                    args.push(OscType::String(ch.describe()));
+                }
+                ret.push(OscMessage {
+                    addr: "/reply/status".into(),
+                    args: Some(args)
+                });
+                for idx in 0..self.cfg.channels.len() {
+                    let mut args = vec![OscType::Int(idx as _)];
+                    if let Some(ref e) = self.engine {
+                        args.extend(e.patched(idx).iter().map(|p| OscType::String(p.clone())));
+                    }
+                    ret.push(OscMessage {
+                        addr: "/reply/status/channel".into(),
+                        args: Some(args)
+                    });
+                }
+            }
+        }
+        ret
+    }
+    pub fn debug_file(&mut self, name: &str) -> Result<Vec<OscMessage>, Error> {
+        info!("Debugging state for file '{}'", name);
+        let file = self.active_files.get(name)
+            .ok_or(CommandError::NotFound("No such active file.".into()))?;
+        let mut ret = vec![];
         info!("senders: {}", file.senders.len());
         info!("buffered: {}", file.buffered);
-        info!("sender 0 alive: {}", file.senders[0].alive());
-        info!("sender 0 active: {}", file.senders[0].active());
-        info!("sender 0 position_samples: {}", file.senders[0].position_samples());
-        info!("volume: {:?}", file.senders[0].volume());
-        Ok(())
+        info!("epoch: {}", file.epoch);
+        ret.push(OscMessage {
+            addr: "/reply/debug/file".into(),
+            args: Some(vec![
+                OscType::String(name.into()),
+                OscType::Int(file.senders.len() as _),
+                OscType::Bool(file.buffered),
+                OscType::Int(file.epoch as _)
+            ])
+        });
+        let time = PlainSender::precise_time_ns();
+        for (i, ch) in file.senders.iter().enumerate() {
+            info!("sender {} alive: {}", i, ch.alive());
+            info!("sender {} active: {}", i, ch.active());
+            info!("sender {} position_samples: {}", i, ch.position_samples());
+            info!("sender {} volume: {:?}", i, ch.volume());
+            ret.push(OscMessage {
+                addr: "/reply/debug/sender".into(),
+                args: Some(vec![
+                    OscType::String(name.into()),
+                    OscType::Int(i as _),
+                    OscType::Bool(ch.alive()),
+                    OscType::Bool(ch.active()),
+                    OscType::Long(ch.position_samples() as _),
+                    OscType::Float(ch.volume().get(time) as _),
+                    OscType::String(format!("{:?}", ch.volume()))
+                ])
+            });
+        }
+        Ok(ret)
+    }
+    pub fn debug_engine(&mut self) -> Vec<OscMessage> {
+        info!("Debugging engine state");
+        let players = self.active_files.values()
+            .map(|f| f.senders.len())
+            .sum::<usize>();
+        info!("sample rate: {}", self.jack_sample_rate());
+        info!("channels: {}", self.cfg.channels.len());
+        info!("active files: {}", self.active_files.len());
+        info!("players: {}", players);
+        info!("epoch: {}", self.epoch);
+        info!("xruns: {}", self.xruns);
+        let mut ret = vec![OscMessage {
+            addr: "/reply/debug/engine".into(),
+            args: Some(vec![
+                OscType::Int(self.jack_sample_rate() as _),
+                OscType::Int(self.cfg.channels.len() as _),
+                OscType::Int(self.active_files.len() as _),
+                OscType::Int(players as _),
+                OscType::Int(self.epoch as _),
+                OscType::Int(self.xruns as _)
+            ])
+        }];
+        let mut names = self.active_files.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            // can't fail, since we know the file is active
+            if let Ok(msgs) = self.debug_file(&name) {
+                ret.extend(msgs);
+            }
+        }
+        ret
     }
     pub fn start_stop_file(&mut self, file: &str, start: bool) -> Result<(), Error> {
+        if start && self.shutdown_at.is_some() {
+            return Err(CommandError::Unavailable("Shutting down.".into()).into());
+        }
         info!("Setting active state to {} for file '{}'", start, file);
         {
             let file = self.active_files.get_mut(file)
-                .ok_or(format_err!("No such active file."))?;
+                .ok_or(CommandError::NotFound("No such active file.".into()))?;
             let time = PlainSender::precise_time_ns();
             for ch in file.senders.iter_mut() {
                 if start {
@@ -178,6 +1138,15 @@ impl Context {
                 }
             }
         }
+        if let Some(ref mut tc) = self.timecode_out {
+            if start {
+                let offset_ms = self.active_files[file].offset_ms;
+                tc.start_file(file, offset_ms);
+            }
+            else {
+                tc.stop_file(file);
+            }
+        }
         if !start {
             self.active_files.remove(file);
         }
@@ -187,7 +1156,7 @@ impl Context {
         info!("Configuring fade (target {:.02}dB, dur {}) for file '{}'", target, dur_ms, file);
         let target = db_lin(target);
         let file = self.active_files.get_mut(file)
-            .ok_or(format_err!("No such active file."))?;
+            .ok_or(CommandError::NotFound("No such active file.".into()))?;
         let time = PlainSender::precise_time_ns();
         let cur_vol = file.senders[0].volume().get(time);
         let mut fd = FadeDetails::new(cur_vol, target as _);
@@ -199,18 +1168,127 @@ impl Context {
         }
         Ok(())
     }
+    pub fn set_file_level(&mut self, file: &str, level: f64) -> Result<(), Error> {
+        info!("Setting level to {:.02}dB for file '{}'", level, file);
+        let level = db_lin(level);
+        let file = self.active_files.get_mut(file)
+            .ok_or(CommandError::NotFound("No such active file.".into()))?;
+        for ch in file.senders.iter_mut() {
+            ch.set_volume(Box::new(Parameter::Raw(level as _)));
+        }
+        Ok(())
+    }
+    /// Makes files with timecode trigger points follow the timecode position `pos`.
+    ///
+    /// Files are started (partway through, if need be) when the timecode is
+    /// within them, relocated if they drift too far from it, and stopped when it
+    /// leaves them or stops. Files stopped by someone else are left alone until
+    /// the timecode leaves them.
+    pub fn chase_timecode(&mut self, pos: Option<u64>) {
+        let (triggers, tolerance_ms, level) = match self.chase {
+            Some(ref c) => (c.triggers.clone(), c.tolerance_ms, c.level),
+            None => return
+        };
+        for (name, start_ms) in triggers {
+            let duration_ms = self.file_info.get(&name).map(|f| f.duration_ms).unwrap_or(0);
+            let looping = self.cfg.files.get(&name).map(|f| f.looping).unwrap_or(false);
+            let want = match pos {
+                Some(p) if p >= start_ms && (looping || p - start_ms < duration_ms) => Some(p - start_ms),
+                _ => None
+            };
+            // A looping file can be any number of passes in; it can only be
+            // located within the first.
+            let want = match want {
+                Some(offset) if looping && duration_ms > 0 => Some(offset % duration_ms),
+                x => x
+            };
+            let located = self.chase.as_ref().unwrap().chased.get(&name).cloned();
+            let res = match (want, located) {
+                (Some(offset), None) => {
+                    info!("Timecode reached file '{}' ({}ms in)", name, offset);
+                    self.locate_file(&name, level, offset)
+                },
+                (Some(offset), Some(when)) => {
+                    let settled = when.elapsed() > ::std::time::Duration::from_millis(Chase::SETTLE_MS);
+                    let position = match self.active_files.get(&name) {
+                        Some(f) if settled && f.state() == "playing" => f.position_ms(self.cfg.sample_rate),
+                        _ => continue
+                    };
+                    let position = if looping && duration_ms > 0 {
+                        position % duration_ms
+                    }
+                    else {
+                        position
+                    };
+                    let drift = position.abs_diff(offset);
+                    if drift > tolerance_ms {
+                        info!("File '{}' drifted {}ms from timecode; relocating", name, drift);
+                        self.locate_file(&name, level, offset)
+                    }
+                    else {
+                        Ok(())
+                    }
+                },
+                (None, Some(_)) => {
+                    self.chase.as_mut().unwrap().chased.remove(&name);
+                    if self.active_files.contains_key(&name) {
+                        info!("Timecode left file '{}'", name);
+                        let ret = self.start_stop_file(&name, false);
+                        self.notify_file(&name, "stopped");
+                        ret
+                    }
+                    else {
+                        Ok(())
+                    }
+                },
+                (None, None) => Ok(())
+            };
+            if let Err(e) = res {
+                warn!("Failed chasing timecode for file '{}': {}", name, e);
+            }
+        }
+    }
+    /// (Re)starts the file `file`, `offset_ms` milliseconds in.
+    ///
+    /// If it was already playing, this counts as it being `relocated`, rather
+    /// than `started` (so that actions for starting don't run again).
+    fn locate_file(&mut self, file: &str, level: f64, offset_ms: u64) -> Result<(), Error> {
+        let relocating = self.active_files.contains_key(file);
+        if relocating {
+            self.start_stop_file(file, false)?;
+        }
+        if let Some(ref mut c) = self.chase {
+            c.chased.insert(file.into(), Instant::now());
+        }
+        self.prepare_file_at(file, level, offset_ms)?;
+        self.start_stop_file(file, true)?;
+        self.notify_file(file, if relocating { "relocated" } else { "started" });
+        Ok(())
+    }
     pub fn prepare_file(&mut self, file: &str, level: f64) -> Result<(), Error> {
-        info!("Preparing to play file '{}' at level {:.02}dB", file, level);
+        self.prepare_file_at(file, level, 0)
+    }
+    /// Like `prepare_file`, but starts playback `offset_ms` milliseconds in.
+    pub fn prepare_file_at(&mut self, file: &str, level: f64, offset_ms: u64) -> Result<(), Error> {
+        if self.shutdown_at.is_some() {
+            return Err(CommandError::Unavailable("Shutting down.".into()).into());
+        }
+        info!("Preparing to play file '{}' at level {:.02}dB, from {}ms", file, level, offset_ms);
         let level = db_lin(level);
         let filename = file.to_string();
         let filename2 = filename.clone();
-        let file = self.cfg.files.get(file).ok_or(format_err!("No such file."))?;
+        let file = self.cfg.files.get(file).ok_or(CommandError::NotFound("No such file.".into()))?;
         let looping = file.looping;
+        let engine = self.engine.as_mut()
+            .ok_or(CommandError::Unavailable("JACK isn't running.".into()))?;
         let mut mf = MediaFile::new(&mut self.mctx, &file.uri)?;
+        if offset_ms > 0 {
+            mf.seek(::sqa_ffmpeg::Duration::milliseconds(offset_ms as i64))?;
+        }
         let mut senders = vec![];
         let mut ctls = vec![];
         for i in 0..self.cfg.channels.len() {
-            let mut send = self.ec.new_sender(mf.sample_rate() as u64);
+            let mut send = engine.ec.new_sender(mf.sample_rate() as u64);
             send.set_output_patch(i);
             send.set_volume(Box::new(Parameter::Raw(level as _)));
             ctls.push(send.make_plain());
@@ -220,21 +1298,21 @@ impl Context {
         let (btx, brx) = channel();
         self.epoch += 1;
         let epo = self.epoch;
-        ::std::thread::spawn(move || {
+        let thread = ::std::thread::spawn(move || {
             info!("Starting buffering thread for file '{}' epoch {}", filename, epo);
-            loop {
-                'outer: for frame in &mut mf {
+            'file: loop {
+                for frame in &mut mf {
                     match frame {
                         Ok(mut frame) => {
                             for (ch, smpl) in &mut frame {
                                 if let Some(s) = senders.get_mut(ch) {
-                                    while let Some(_) = s.buf.try_push(smpl.f32()) {
+                                    while s.buf.try_push(smpl.f32()).is_some() {
                                         let msg = brx.recv();
                                         match msg {
                                             Ok(BufferingMessage::Continue) => {},
                                             Ok(BufferingMessage::Die) | Err(_) => {
                                                 info!("File '{}' buffering ended prematurely", filename);
-                                                break 'outer;
+                                                break 'file;
                                             }
                                         }
                                     }
@@ -253,11 +1331,12 @@ impl Context {
                     }
                 }
                 else {
-                    break;
+                    break 'file;
                 }
             }
             info!("File '{}' epoch {} finished buffering", filename, epo);
-            txc.send(Message::BufferComplete(filename.clone(), epo)).unwrap();
+            // This fails if we're shutting down, which is fine.
+            let _ = txc.send(Message::BufferComplete(filename.clone(), epo));
             while let Ok(x) = brx.recv() {
                 if let BufferingMessage::Die = x {
                     break;
@@ -269,21 +1348,205 @@ impl Context {
             senders: ctls,
             buffered: false,
             epoch: self.epoch,
-            tx: btx
+            offset_ms,
+            loops: 0,
+            marker_from: None,
+            tx: btx,
+            thread: Some(thread)
         });
         Ok(())
     }
-    pub fn shutdown(&mut self) -> ! {
-        warn!("Shutting down...");
-        panic!("Shutdown requested!");
+    /// Starts shutting down, by fading every active file out over `shutdown_secs`.
+    ///
+    /// `run` returns once that's done (or once nothing's playing any more). If
+    /// we're already shutting down, it returns straight away instead.
+    pub fn shutdown(&mut self) {
+        if self.shutdown_at.is_some() {
+            warn!("Shutdown requested again; stopping now");
+            self.shutdown_at = Some(Instant::now());
+            return;
+        }
+        let secs = self.cfg.shutdown_secs;
+        warn!("Shutting down in {} seconds...", secs);
+        self.notify(OscMessage {
+            addr: "/event/shutdown".into(),
+            args: Some(vec![OscType::Int(secs as _)])
+        });
+        let mut names = self.active_files.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            if let Err(e) = self.configure_file_fade(&name, f64::NEG_INFINITY, secs * 1000) {
+                warn!("Failed fading out file '{}': {}", name, e);
+            }
+            self.notify_file(&name, "fading");
+        }
+        self.shutdown_at = Some(Instant::now() + Duration::from_secs(secs));
+    }
+    /// Whether we're shutting down, and it's time to stop.
+    fn shutdown_due(&self) -> bool {
+        match self.shutdown_at {
+            Some(at) => self.active_files.is_empty() || Instant::now() >= at,
+            None => false
+        }
+    }
+    /// Notifies subscribers that output channel `idx` was connected to `port`,
+    /// or disconnected from it.
+    pub fn notify_channel(&mut self, idx: usize, port: &str, connected: bool) {
+        self.notify(OscMessage {
+            addr: "/event/channel".into(),
+            args: Some(vec![
+                OscType::Int(idx as _),
+                OscType::String(port.into()),
+                OscType::String(if connected { "connected" } else { "disconnected" }.into())
+            ])
+        });
+    }
+    /// Brings the output channels' connections up to date with `cfg.channels`
+    /// (see `Engine::repatch`).
+    pub fn repatch(&mut self) {
+        let changes = match self.engine {
+            Some(ref mut e) => e.repatch(&self.cfg.channels),
+            None => return
+        };
+        for (idx, port, connected) in changes {
+            self.notify_channel(idx, &port, connected);
+        }
+    }
+    /// JACK's sample rate, or 0 if it isn't running.
+    pub fn jack_sample_rate(&self) -> u64 {
+        self.engine.as_ref().map(|e| e.ec.conn.sample_rate() as u64).unwrap_or(0)
+    }
+    /// Deals with the JACK server going away: everything stops, and we start
+    /// trying to reconnect (see `reconnect`).
+    pub fn jack_lost(&mut self, reason: &str) {
+        if self.engine.is_none() {
+            return;
+        }
+        error!("JACK server shut down ({}); waiting for it to come back", reason);
+        self.notify(OscMessage {
+            addr: "/event/jack".into(),
+            args: Some(vec![
+                OscType::String("down".into()),
+                OscType::String(reason.into())
+            ])
+        });
+        // Don't wait for the buffering threads: they'll stop soon enough, and
+        // there's nothing for them to finish now that JACK's gone.
+        self.stop_all(false);
+        self.engine = None;
+        self.jack_up.store(false, Ordering::SeqCst);
+        // The other JACK clients have gone too.
+        self.midi_in = None;
+        self.timecode_in = None;
+        self.timecode_out = None;
+        self.reconnect_at = Some(Instant::now() + Duration::from_secs(RECONNECT_SECS));
+    }
+    /// Tries to start the audio engine (again), if it isn't running and it's time to.
+    fn reconnect(&mut self) {
+        if self.engine.is_some() || self.shutdown_at.is_some() {
+            return;
+        }
+        if self.reconnect_at.map(|at| Instant::now() < at).unwrap_or(false) {
+            return;
+        }
+        match engine::start(&self.cfg, self.tx.clone()) {
+            Ok(e) => {
+                info!("[+] Audio engine started");
+                self.engine = Some(e);
+                self.jack_up.store(true, Ordering::SeqCst);
+                self.reconnect_error = None;
+            },
+            Err(e) => {
+                let e = e.to_string();
+                if self.reconnect_error.as_ref() != Some(&e) {
+                    warn!("Failed starting audio engine (retrying every {}s): {}", RECONNECT_SECS, e);
+                }
+                else {
+                    debug!("Failed starting audio engine: {}", e);
+                }
+                self.reconnect_error = Some(e);
+                self.reconnect_at = Some(Instant::now() + Duration::from_secs(RECONNECT_SECS));
+                return;
+            }
+        }
+        self.start_jack_clients();
+        self.notify(OscMessage {
+            addr: "/event/jack".into(),
+            args: Some(vec![OscType::String("up".into())])
+        });
+    }
+    /// Starts the MIDI input and timecode input and output, as configured.
+    ///
+    /// Their JACK clients go away with the JACK server, so this gets done every
+    /// time the audio engine starts. Failures are logged, rather than stopping
+    /// the engine from starting.
+    fn start_jack_clients(&mut self) {
+        self.start_midi();
+        self.timecode_in = None;
+        if let Some(ref t) = self.cfg.timecode {
+            info!("[+] Starting timecode input ({:?})", t.source);
+            match timecode::start(t, self.tx.clone()) {
+                Ok(tc) => self.timecode_in = Some(tc),
+                Err(e) => warn!("Failed starting timecode input: {}", e)
+            }
+        }
+        self.timecode_out = None;
+        if let Some(ref t) = self.cfg.timecode_out {
+            info!("[+] Starting timecode output");
+            match timecode::start_output(t, &self.cfg.files) {
+                Ok(tc) => self.timecode_out = Some(tc),
+                Err(e) => warn!("Failed starting timecode output: {}", e)
+            }
+        }
+    }
+    /// (Re)starts the MIDI input, if it's configured.
+    fn start_midi(&mut self) {
+        self.midi_in = None;
+        if let Some(ref m) = self.cfg.midi {
+            info!("[+] Starting MIDI input");
+            match midi::start(m, self.midi.clone(), self.tx.clone(), self.jack_up.clone()) {
+                Ok(m) => self.midi_in = Some(m),
+                Err(e) => warn!("Failed starting MIDI input: {}", e)
+            }
+        }
+    }
+    /// Stops every active file, and (if `wait` is set) waits for their buffering
+    /// threads to finish.
+    fn stop_all(&mut self, wait: bool) {
+        let mut names = self.active_files.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            // Dropping the file tells its buffering thread to stop.
+            let thread = {
+                let mut file = self.active_files.remove(&name).unwrap();
+                for ch in file.senders.iter_mut() {
+                    ch.set_active(false);
+                }
+                file.thread.take()
+            };
+            if let Some(ref mut tc) = self.timecode_out {
+                tc.stop_file(&name);
+            }
+            if let Some(t) = thread.filter(|_| wait) {
+                if t.join().is_err() {
+                    warn!("Buffering thread for file '{}' panicked", name);
+                }
+            }
+            self.notify_file(&name, "stopped");
+        }
     }
-    pub fn run(&mut self) -> ! {
+    /// Processes messages until we're told to shut down (see `shutdown`), then
+    /// stops everything.
+    pub fn run(&mut self) {
+        info!("[+] Initialising SQA Engine");
+        self.reconnect();
         info!("[+] Up and running!");
         loop {
             let res = self.rx.recv();
             match res {
                 Err(_) => {
-                    panic!("Channel split; performing shutdown");
+                    error!("Channel split; performing shutdown");
+                    break;
                 },
                 Ok(m) => {
                     if let Err(e) = self.process_message(m) {
@@ -291,6 +1554,11 @@ impl Context {
                     }
                 }
             }
+            if self.shutdown_due() {
+                break;
+            }
         }
+        info!("[+] Stopping {} active files", self.active_files.len());
+        self.stop_all(true);
     }
 }
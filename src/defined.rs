@@ -0,0 +1,54 @@
+//! Files defined at runtime (with `/file/NAME/define`), and saving them to disk.
+//!
+//! These live alongside the files from the config file and library, and get
+//! added back in every time the config is reloaded. If `defined_files` is set,
+//! they're also written out to that file (as JSON) whenever they change, and
+//! read back in at startup.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use config::{Config, DefinedFile};
+use failure::Error;
+use serde_json;
+
+/// Reads the defined files saved in `path`, if it exists.
+pub fn load(path: &str) -> Result<HashMap<String, DefinedFile>, Error> {
+    if !Path::new(path).exists() {
+        return Ok(HashMap::new());
+    }
+    let file = File::open(path)
+        .map_err(|e| format_err!("failed opening defined files '{}': {}", path, e))?;
+    let ret = serde_json::from_reader(file)
+        .map_err(|e| format_err!("failed reading defined files '{}': {}", path, e))?;
+    Ok(ret)
+}
+/// Saves `defined` to `path`, replacing whatever was there.
+pub fn save(path: &str, defined: &HashMap<String, DefinedFile>) -> Result<(), Error> {
+    // Sorted, so that the file doesn't get shuffled around every time.
+    let sorted = defined.iter().collect::<BTreeMap<_, _>>();
+    let tmp = format!("{}.tmp", path);
+    {
+        let mut file = File::create(&tmp)
+            .map_err(|e| format_err!("failed creating '{}': {}", tmp, e))?;
+        serde_json::to_writer_pretty(&mut file, &sorted)?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+        .map_err(|e| format_err!("failed replacing '{}': {}", path, e))?;
+    Ok(())
+}
+/// Adds the files in `defined` to `cfg`.
+///
+/// Files in the config file (or library) with the same name win.
+pub fn apply(cfg: &mut Config, defined: &HashMap<String, DefinedFile>) {
+    for (name, def) in defined.iter() {
+        if cfg.files.contains_key(name) {
+            warn!("File '{}' is defined at runtime, but is also in the config file; using the config file's", name);
+            continue;
+        }
+        cfg.files.insert(name.clone(), def.to_playback_file());
+    }
+}
@@ -0,0 +1,228 @@
+//! Starting the audio engine, patching its outputs, and noticing when the JACK
+//! server goes away.
+//!
+//! sqa-engine doesn't tell us when JACK shuts down, so a separate JACK client
+//! (with no ports) listens for that, and sets a flag that the `Context` checks
+//! every tick; it then tries to start the engine again every so often. The same client tells it when
+//! ports come and go, so that outputs can be reconnected to ports that weren't
+//! there before (e.g. a USB interface that got plugged back in).
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use sqa_engine::EngineContext;
+use jack::{self, Client, ClientOptions, ClientStatus, AsyncClient, PortId, PortFlags};
+use ctxt::{CommandError, Message};
+use config::{Config, OneOrMany, PortSpec};
+use regex::Regex;
+use failure::Error;
+
+/// The name of the JACK client used for the audio engine.
+pub const CLIENT_NAME: &str = "mfl-gramophone";
+/// The name of the JACK client used to watch the server.
+pub const WATCHDOG_CLIENT_NAME: &str = "mfl-gramophone-watchdog";
+
+/// Tells the `Context` when the JACK server shuts down, or ports come and go.
+pub struct Watchdog {
+    // JACK wants notification handlers to be `Sync`.
+    tx: Mutex<Sender<Message>>,
+    /// Set when the server shuts down.
+    shut_down: Arc<AtomicBool>
+}
+impl jack::NotificationHandler for Watchdog {
+    fn shutdown(&mut self, _: ClientStatus, _: &str) {
+        // This has to be safe to run like a signal handler, so no locking or
+        // allocating: just set a flag, for `Engine::has_shut_down`.
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+    fn port_registration(&mut self, _: &Client, _: PortId, _: bool) {
+        let _ = self.tx.lock().unwrap().send(Message::PortsChanged);
+    }
+}
+/// A running audio engine. Dropping this disconnects it from JACK.
+pub struct Engine {
+    pub ec: EngineContext,
+    /// The engine's channel numbers, for each output channel.
+    chans: Vec<usize>,
+    /// The ports each output channel is connected to.
+    patched: Vec<Vec<String>>,
+    watchdog: AsyncClient<Watchdog, ()>,
+    shut_down: Arc<AtomicBool>
+}
+impl Engine {
+    /// Whether the JACK server has shut down since the engine started.
+    pub fn has_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::SeqCst)
+    }
+    /// Which ports output channel `idx` is connected to.
+    pub fn patched(&self, idx: usize) -> &[String] {
+        self.patched.get(idx).map(|p| p as &[String]).unwrap_or(&[])
+    }
+    /// Finds the names of the ports `specs` refer to, that are there right now.
+    fn resolve(&self, specs: &[PortSpec]) -> Vec<String> {
+        let mut ret: Vec<String> = vec![];
+        for spec in specs.iter() {
+            let found = match *spec {
+                PortSpec::Name(ref n) => {
+                    if self.ec.conn.get_port_by_name(n).is_ok() { vec![n.clone()] } else { vec![] }
+                },
+                PortSpec::Regex { ref regex } => {
+                    // The config's been validated, so this shouldn't fail.
+                    let re = match Regex::new(regex) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            warn!("Invalid port regex /{}/: {}", regex, e);
+                            continue;
+                        }
+                    };
+                    // Don't let a regex loop our outputs (or anything else of
+                    // ours) back into themselves.
+                    self.watchdog.as_client().ports(None, Some("audio"), PortFlags::IS_INPUT)
+                        .into_iter()
+                        .filter(|p| !p.starts_with(CLIENT_NAME))
+                        .filter(|p| re.is_match(p))
+                        .collect()
+                }
+            };
+            for port in found {
+                if !ret.contains(&port) {
+                    ret.push(port);
+                }
+            }
+        }
+        ret
+    }
+    /// Connects output channel `idx` to the port `dest`.
+    fn connect(&mut self, idx: usize, dest: &str) -> Result<(), Error> {
+        let port = self.ec.conn.get_port_by_name(dest)
+            .map_err(|e| format_err!("getting port {} failed: {:?}", dest, e))?;
+        let chan = self.ec.chans.get(self.chans[idx]).unwrap().as_ref().unwrap();
+        self.ec.conn.connect_ports(chan, &port)
+            .map_err(|e| format_err!("patching channel {} to {} failed: {:?}", idx, dest, e))?;
+        info!("Channel {} connected to {}", idx, dest);
+        self.patched[idx].push(dest.into());
+        Ok(())
+    }
+    /// Disconnects output channel `idx` from the port `dest`.
+    fn disconnect(&mut self, idx: usize, dest: &str) {
+        self.patched[idx].retain(|p| p != dest);
+        let chan = self.ec.chans.get(self.chans[idx]).unwrap().as_ref().unwrap();
+        // If the port's gone, JACK has already disconnected it.
+        if let Ok(port) = self.ec.conn.get_port_by_name(dest) {
+            match self.ec.conn.disconnect_ports(chan, &port) {
+                Ok(_) => info!("Channel {} disconnected from {}", idx, dest),
+                Err(e) => warn!("Failed disconnecting channel {} from {}: {:?}", idx, dest, e)
+            }
+        }
+    }
+    /// Makes output channel `idx` connected to every port `specs` refers to
+    /// (that's there), and nothing else.
+    ///
+    /// Returns the ports that were connected (`true`) or disconnected (`false`).
+    fn repatch_channel(&mut self, idx: usize, specs: &[PortSpec]) -> Vec<(String, bool)> {
+        let mut ret = vec![];
+        let wanted = self.resolve(specs);
+        for port in self.patched[idx].clone() {
+            if !wanted.contains(&port) {
+                if self.ec.conn.get_port_by_name(&port).is_err() {
+                    warn!("Channel {}'s port {} has gone away", idx, port);
+                }
+                self.disconnect(idx, &port);
+                ret.push((port, false));
+            }
+        }
+        for port in wanted {
+            if self.patched[idx].contains(&port) {
+                continue;
+            }
+            match self.connect(idx, &port) {
+                Ok(_) => ret.push((port, true)),
+                Err(e) => warn!("{}", e)
+            }
+        }
+        ret
+    }
+    /// Connects output channel `idx` to the ports `specs` refers to, instead of
+    /// whatever it was connected to before.
+    ///
+    /// Fails (without changing anything) if any ports given by name aren't there.
+    pub fn patch(&mut self, idx: usize, specs: &[PortSpec]) -> Result<Vec<(String, bool)>, Error> {
+        if idx >= self.chans.len() {
+            return Err(CommandError::NotFound(format!("There's no channel {}.", idx)).into());
+        }
+        for spec in specs.iter() {
+            if let PortSpec::Name(ref n) = *spec {
+                self.ec.conn.get_port_by_name(n)
+                    .map_err(|e| CommandError::NotFound(format!("getting port {} failed: {:?}", n, e)))?;
+            }
+        }
+        Ok(self.repatch_channel(idx, specs))
+    }
+    /// Makes each output channel's connections match `channels`, as far as
+    /// possible: ports that have gone away are forgotten about, and ports that
+    /// have appeared (and match) get connected.
+    ///
+    /// Returns the ports that were connected (`true`) or disconnected (`false`),
+    /// for each channel.
+    pub fn repatch(&mut self, channels: &[OneOrMany<PortSpec>]) -> Vec<(usize, String, bool)> {
+        let mut ret = vec![];
+        for (idx, specs) in channels.iter().enumerate().take(self.chans.len()) {
+            for (port, connected) in self.repatch_channel(idx, &specs.to_vec()) {
+                ret.push((idx, port, connected));
+            }
+        }
+        ret
+    }
+}
+/// Starts the audio engine, makes its channels, and patches them to `cfg.channels`.
+///
+/// Channels whose ports aren't there yet are left unconnected, to be connected
+/// by `Engine::repatch` when they turn up.
+pub fn start(cfg: &Config, tx: Sender<Message>) -> Result<Engine, Error> {
+    let mut ec = EngineContext::new(Some(CLIENT_NAME))
+        .map_err(|e| format_err!("failed starting SQA Engine: {:?}", e))?;
+    if ec.conn.sample_rate() as u64 != cfg.sample_rate {
+        bail!("JACK sample rate ({}) doesn't match configured ({})", ec.conn.sample_rate(), cfg.sample_rate);
+    }
+    let mut hdl = ec.get_handle().expect("new engine has no handle");
+    let txc = tx.clone();
+    ::std::thread::spawn(move || {
+        loop {
+            let msg = hdl.recv();
+            if txc.send(Message::Engine(msg)).is_err() {
+                break;
+            }
+        }
+    });
+    let mut chans = vec![];
+    for i in 0..cfg.channels.len() {
+        let st = format!("channel {}", i);
+        let p = ec.new_channel(&st)
+            .map_err(|e| format_err!("making channel {} failed: {:?}", i, e))?;
+        chans.push(p);
+    }
+    let (client, _) = Client::new(WATCHDOG_CLIENT_NAME, ClientOptions::NO_START_SERVER)
+        .map_err(|e| format_err!("failed creating JACK client: {:?}", e))?;
+    let shut_down = Arc::new(AtomicBool::new(false));
+    let handler = Watchdog {
+        tx: Mutex::new(tx),
+        shut_down: shut_down.clone()
+    };
+    let watchdog = client.activate_async(handler, ())
+        .map_err(|e| format_err!("failed activating JACK client: {:?}", e))?;
+    let mut ret = Engine {
+        ec,
+        patched: vec![vec![]; chans.len()],
+        chans,
+        watchdog,
+        shut_down
+    };
+    for (i, ch) in cfg.channels.iter().enumerate() {
+        info!("[+] Setting up channel {} (-> {})...", i, ch.describe());
+        ret.repatch_channel(i, &ch.to_vec());
+        if ret.patched(i).is_empty() {
+            warn!("Channel {} isn't connected to anything yet; it will be when its ports appear", i);
+        }
+    }
+    Ok(ret)
+}
@@ -0,0 +1,376 @@
+//! An HTTP/JSON API, as an alternative to OSC.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError, TrySendError};
+use std::time::{Duration, Instant};
+use tiny_http::{Server, Request, Response, Method, Header};
+use serde_json::{self, Value};
+use rosc::{OscMessage, OscType};
+use ctxt::{Message, Query};
+use osc::{self, Replier, ConnectionCount};
+use config::DefinedFile;
+use auth::AccessControl;
+use failure::Error;
+
+/// How long to wait for the engine to process a command before giving up.
+const RESULT_TIMEOUT_MS: u64 = 5000;
+/// How many events can be waiting to be written to an event stream before the
+/// client is assumed to have stopped reading.
+const EVENT_QUEUE_LEN: usize = 256;
+
+/// Converts an OSC argument into its JSON equivalent.
+pub fn osc_to_json(arg: &OscType) -> Value {
+    match *arg {
+        OscType::Int(i) => Value::from(i),
+        OscType::Long(i) => Value::from(i),
+        OscType::Float(f) => Value::from(f),
+        OscType::Double(f) => Value::from(f),
+        OscType::String(ref s) => Value::from(s.clone()),
+        OscType::Bool(b) => Value::from(b),
+        OscType::Nil => Value::Null,
+        ref x => Value::from(format!("{:?}", x))
+    }
+}
+/// Converts an OSC message into a JSON object with `addr` and `args` keys.
+pub fn message_to_json(msg: &OscMessage) -> Value {
+    let args = msg.args.as_ref()
+        .map(|a| a.iter().map(osc_to_json).collect::<Vec<_>>())
+        .unwrap_or(vec![]);
+    json!({
+        "addr": msg.addr,
+        "args": args
+    })
+}
+/// Decodes `%XX` escapes in a URL path segment.
+pub fn percent_decode(seg: &str) -> String {
+    let bytes = seg.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            if let Some(b) = hex {
+                ret.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        ret.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+fn get_f64(body: &Value, key: &str) -> Result<f64, String> {
+    body.get(key)
+        .and_then(|v| v.as_f64().or_else(|| v.as_str().and_then(|s| s.trim().parse().ok())))
+        .ok_or(format!("expected a number for '{}'", key))
+}
+/// Like `get_f64`, but returns `None` if `key` is missing.
+fn get_optional_f64(body: &Value, key: &str) -> Result<Option<f64>, String> {
+    match body.get(key) {
+        Some(&Value::Null) | None => Ok(None),
+        Some(_) => get_f64(body, key).map(Some)
+    }
+}
+/// Sends `msg` to the engine, and waits for it to be processed.
+///
+/// `reply` should be a `Replier::Channel` whose other end is `rrx`. Returns the
+/// replies the command generated, or an HTTP status code and error message.
+pub fn run_command(tx: &Sender<Message>, msg: Message, reply: Replier, rrx: Receiver<OscMessage>) -> Result<Vec<OscMessage>, (u16, String)> {
+    tx.send(Message::WithResult(Box::new(msg), reply))
+        .map_err(|_| (503, "engine has gone away".to_string()))?;
+    let mut replies = vec![];
+    loop {
+        match rrx.recv_timeout(Duration::from_millis(RESULT_TIMEOUT_MS)) {
+            Ok(msg) => {
+                match &msg.addr as &str {
+                    "/ack" => break,
+                    "/error" => {
+                        let err = msg.args.as_ref()
+                            .and_then(|a| a.first())
+                            .map(|a| match *a {
+                                OscType::String(ref s) => s.clone(),
+                                ref x => format!("{:?}", x)
+                            })
+                            .unwrap_or("unknown error".into());
+                        // The second argument says what kind of error it was
+                        // (see `ctxt::CommandError::kind`).
+                        let code = match msg.args.as_ref().and_then(|a| a.get(1)) {
+                            Some(OscType::String(kind)) => match kind as &str {
+                                "not_found" => 404,
+                                "bad_request" => 400,
+                                "unavailable" => 503,
+                                _ => 500
+                            },
+                            _ => 500
+                        };
+                        return Err((code, err));
+                    },
+                    _ => replies.push(msg)
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                return Err((504, "timed out waiting for a result".into()));
+            },
+            Err(RecvTimeoutError::Disconnected) => {
+                // This happens if the command shut the server down.
+                break;
+            }
+        }
+    }
+    Ok(replies)
+}
+
+pub struct HttpContext {
+    pub server: Server,
+    pub tx: Sender<Message>,
+    pub acl: Arc<AccessControl>
+}
+impl HttpContext {
+    fn respond_json(req: Request, code: u16, val: Value) {
+        let resp = Response::from_string(val.to_string())
+            .with_status_code(code)
+            .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+        if let Err(e) = req.respond(resp) {
+            warn!("Failed to send HTTP response: {}", e);
+        }
+    }
+    fn respond_error(req: Request, code: u16, err: &str) {
+        Self::respond_json(req, code, json!({
+            "ok": false,
+            "error": err
+        }));
+    }
+    /// Works out which `Message` an HTTP request corresponds to.
+    fn parse_request(method: &Method, path: &[String], body: &Value, reply: &Replier) -> Result<Message, (u16, String)> {
+        let path = path.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+        let msg = match (method, &path as &[&str]) {
+            (&Method::Post, ["ping"]) => Message::Ping,
+            (&Method::Post, ["shutdown"]) => Message::Shutdown,
+            (&Method::Post, ["reload"]) => Message::Reload(reply.clone()),
+            (&Method::Get, ["debug"]) => Message::Debug(reply.clone()),
+            (&Method::Get, ["query", what]) => {
+                let q = match *what {
+                    "files" => Query::Files,
+                    "active" => Query::Active,
+                    "status" => Query::Status,
+                    "namespace" => Query::Namespace,
+                    _ => return Err((404, format!("no such query '{}'", what)))
+                };
+                Message::Query(q, reply.clone())
+            },
+            (&Method::Post, ["file", name, "start"]) => {
+                let level = get_optional_f64(body, "level").map_err(|e| (400, e))?;
+                Message::PlayFile(name.to_string(), level)
+            },
+            (&Method::Post, ["file", name, "load"]) => {
+                let level = get_optional_f64(body, "level").map_err(|e| (400, e))?;
+                Message::LoadFile(name.to_string(), level)
+            },
+            (&Method::Post, ["file", name, "define"]) => {
+                let uri = body.get("uri")
+                    .and_then(|v| v.as_str())
+                    .ok_or((400, "expected a string for 'uri'".to_string()))?;
+                let looping = match body.get("looping") {
+                    Some(&Value::Null) | None => false,
+                    Some(v) => v.as_bool().ok_or((400, "expected a boolean for 'looping'".to_string()))?
+                };
+                let def = DefinedFile { uri: uri.into(), looping };
+                Message::DefineFile(name.to_string(), def, reply.clone())
+            },
+            (&Method::Post, ["file", name, "undefine"]) => {
+                Message::UndefineFile(name.to_string())
+            },
+            (&Method::Post, ["file", name, "stop"]) => {
+                Message::StopFile(name.to_string())
+            },
+            (&Method::Post, ["file", name, "fade"]) => {
+                let level = get_f64(body, "level").map_err(|e| (400, e))?;
+                let dur = get_f64(body, "duration").map_err(|e| (400, e))?;
+                if dur < 0.0 {
+                    return Err((400, "expected a non-negative number for 'duration'".into()));
+                }
+                Message::FadeFile(name.to_string(), level, dur.round() as u64)
+            },
+            (&Method::Post, ["channel", idx, "patch"]) => {
+                let idx = idx.parse::<usize>()
+                    .map_err(|_| (404, format!("no such channel '{}'", idx)))?;
+                let ports = match body.get("ports").and_then(|v| v.as_array()) {
+                    Some(arr) => arr.iter()
+                        .map(|v| v.as_str().map(|s| s.to_string()))
+                        .collect::<Option<Vec<_>>>(),
+                    None => body.get("port").and_then(|v| v.as_str()).map(|s| vec![s.to_string()])
+                };
+                let ports = match ports {
+                    Some(ref p) if !p.is_empty() => p.clone(),
+                    _ => return Err((400, "expected a string for 'port', or a list of them for 'ports'".into()))
+                };
+                Message::PatchChannel(idx, ports)
+            },
+            (&Method::Post, ["send", action]) => {
+                Message::SendAction(action.to_string(), vec![])
+            },
+            (&Method::Get, ["file", name, "debug"]) => {
+                Message::DebugFile(name.to_string(), reply.clone())
+            },
+            _ => return Err((404, "no such endpoint".into()))
+        };
+        Ok(msg)
+    }
+    /// Streams events to the client as Server-Sent Events, until it goes away.
+    ///
+    /// tiny_http doesn't let us set a timeout on the socket, so the writing is
+    /// done on another thread; if a write takes longer than
+    /// `osc::STREAM_WRITE_TIMEOUT_MS`, fails, or events pile up, the client is
+    /// unsubscribed.
+    fn stream_events(req: Request, tx: Sender<Message>) -> Result<(), Error> {
+        let (rtx, rrx) = mpsc::channel();
+        let name = format!("http:{}", req.remote_addr());
+        let reply = Replier::Channel {
+            tx: rtx,
+            name: name.clone()
+        };
+        tx.send(Message::Subscribe(reply))
+            .map_err(|_| format_err!("engine has gone away"))?;
+        let mut writer = req.into_writer();
+        let (wtx, wrx) = mpsc::sync_channel::<OscMessage>(EVENT_QUEUE_LEN);
+        // When the write in progress (if any) started.
+        let writing = Arc::new(Mutex::new(None));
+        let writing2 = writing.clone();
+        ::std::thread::spawn(move || {
+            let mut write = |data: &[u8]| -> ::std::io::Result<()> {
+                *writing2.lock().unwrap() = Some(Instant::now());
+                writer.write_all(data)?;
+                writer.flush()?;
+                *writing2.lock().unwrap() = None;
+                Ok(())
+            };
+            if write(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n").is_err() {
+                return;
+            }
+            for msg in wrx.iter() {
+                if write(format!("data: {}\n\n", message_to_json(&msg)).as_bytes()).is_err() {
+                    return;
+                }
+            }
+        });
+        let timeout = Duration::from_millis(osc::STREAM_WRITE_TIMEOUT_MS);
+        let ret = loop {
+            match rrx.recv_timeout(timeout) {
+                Ok(msg) => match wtx.try_send(msg) {
+                    Ok(_) => {},
+                    Err(TrySendError::Full(_)) => break Err(format_err!("client isn't reading events")),
+                    Err(TrySendError::Disconnected(_)) => break Err(format_err!("writing to client failed"))
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break Ok(())
+            }
+            if let Some(start) = *writing.lock().unwrap() {
+                if start.elapsed() > timeout {
+                    break Err(format_err!("timed out writing to client"));
+                }
+            }
+        };
+        // Unsubscribe by name: holding on to a sender for the subscription
+        // would stop us noticing if the `Context` dropped it.
+        let _ = tx.send(Message::Unsubscribe(Replier::Discard { name }));
+        ret
+    }
+    fn handle_request(mut req: Request, tx: Sender<Message>, acl: Arc<AccessControl>) {
+        info!("HTTP {} {} from {}", req.method(), req.url(), req.remote_addr());
+        let authorized = {
+            let auth = req.headers().iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.as_str());
+            acl.check_bearer(auth)
+        };
+        if !authorized {
+            acl.reject(&format!("http:{}", req.remote_addr()), "missing or incorrect token");
+            Self::respond_error(req, 401, "missing or incorrect token");
+            return;
+        }
+        let path = req.url().split('?').next().unwrap_or("")
+            .split('/')
+            .filter(|x| !x.is_empty())
+            .map(percent_decode)
+            .collect::<Vec<_>>();
+        if *req.method() == Method::Get && path == ["events"] {
+            if let Err(e) = Self::stream_events(req, tx) {
+                info!("Event stream closed: {}", e);
+            }
+            return;
+        }
+        let mut body = String::new();
+        if let Err(e) = req.as_reader().read_to_string(&mut body) {
+            Self::respond_error(req, 400, &format!("failed to read body: {}", e));
+            return;
+        }
+        let body: Value = if body.trim().is_empty() {
+            json!({})
+        }
+        else {
+            match serde_json::from_str(&body) {
+                Ok(v) => v,
+                Err(e) => {
+                    Self::respond_error(req, 400, &format!("invalid JSON: {}", e));
+                    return;
+                }
+            }
+        };
+        let (rtx, rrx) = mpsc::channel();
+        let reply = Replier::Channel {
+            tx: rtx,
+            name: format!("http:{}", req.remote_addr())
+        };
+        let msg = match Self::parse_request(req.method(), &path, &body, &reply) {
+            Ok(m) => m,
+            Err((code, e)) => {
+                Self::respond_error(req, code, &e);
+                return;
+            }
+        };
+        match run_command(&tx, msg, reply, rrx) {
+            Ok(replies) => {
+                Self::respond_json(req, 200, json!({
+                    "ok": true,
+                    "replies": replies.iter().map(message_to_json).collect::<Vec<_>>()
+                }));
+            },
+            Err((code, e)) => Self::respond_error(req, code, &e)
+        }
+    }
+    pub fn run(&mut self) -> ! {
+        let connections = ConnectionCount::default();
+        loop {
+            match self.server.recv() {
+                Ok(req) => {
+                    if !self.acl.source_allowed(req.remote_addr().ip()) {
+                        self.acl.reject(&format!("http:{}", req.remote_addr()), "source address not allowed");
+                        Self::respond_error(req, 403, "source address not allowed");
+                        continue;
+                    }
+                    let guard = match connections.acquire() {
+                        Some(g) => g,
+                        None => {
+                            warn!("Turning away HTTP request from {}: already handling {} requests", req.remote_addr(), osc::MAX_CONNECTIONS);
+                            Self::respond_error(req, 503, "too many requests at once");
+                            continue;
+                        }
+                    };
+                    let tx = self.tx.clone();
+                    let acl = self.acl.clone();
+                    ::std::thread::spawn(move || {
+                        let _guard = guard;
+                        Self::handle_request(req, tx, acl);
+                    });
+                },
+                Err(e) => {
+                    error!("Error receiving HTTP request: {}", e);
+                    ::std::thread::sleep(Duration::from_millis(1000));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//! Registering files by scanning directories, as configured in `[[library]]`.
+//!
+//! Each file found is named after its path relative to the library directory,
+//! without its extension, with directory separators replaced by `.` (so
+//! `sfx/door.wav` becomes `sfx.door`). Characters that have a special meaning in
+//! OSC addresses or patterns are replaced by `_`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use config::{Config, LibraryDir, PlaybackFile};
+use pattern;
+use osc;
+use failure::Error;
+
+/// Turns `rel` (a path relative to a library directory) into a file name.
+fn file_name(prefix: &str, rel: &Path) -> String {
+    let mut parts = rel.parent()
+        .map(|p| p.iter().map(|c| c.to_string_lossy().into_owned()).collect::<Vec<_>>())
+        .unwrap_or(vec![]);
+    parts.push(rel.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+    let name = parts.join(".")
+        .chars()
+        .map(|c| if osc::RESERVED_CHARS.contains(c) { '_' } else { c })
+        .collect::<String>();
+    format!("{}{}", prefix, name)
+}
+/// Finds every file under `dir` (in `rel`, relative to it) that `lib` wants.
+///
+/// `visited` holds the (canonical) directories scanned so far, so that
+/// symlinks can't send this round in circles.
+fn scan_dir(lib: &LibraryDir, dir: &Path, rel: &Path, visited: &mut HashSet<PathBuf>, out: &mut Vec<(String, PlaybackFile)>) -> Result<(), Error> {
+    let canonical = fs::canonicalize(dir)
+        .map_err(|e| format_err!("failed reading library directory '{}': {}", dir.display(), e))?;
+    if !visited.insert(canonical) {
+        warn!("Not scanning library directory '{}' again (it's a symlink loop, or a link to somewhere already scanned)", dir.display());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(dir)
+        .map_err(|e| format_err!("failed reading library directory '{}': {}", dir.display(), e))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for ent in entries {
+        let fname = ent.file_name().to_string_lossy().into_owned();
+        if fname.starts_with('.') {
+            continue;
+        }
+        let path = ent.path();
+        let rel = rel.join(&fname);
+        if path.is_dir() {
+            if lib.recursive {
+                scan_dir(lib, &path, &rel, visited, out)?;
+            }
+            continue;
+        }
+        if !pattern::matches(&lib.pattern, &fname) && !pattern::matches(&lib.pattern, &fname.to_lowercase()) {
+            continue;
+        }
+        let pf = PlaybackFile {
+            uri: path.to_string_lossy().into_owned(),
+            looping: lib.looping,
+            group: lib.group.clone(),
+            level: lib.level,
+            ..Default::default()
+        };
+        out.push((file_name(lib.prefix.as_ref().map(|x| x as &str).unwrap_or(""), &rel), pf));
+    }
+    Ok(())
+}
+/// Scans every library directory in `cfg`, and adds the files found to
+/// `cfg.files`.
+///
+/// Files listed explicitly in `[files]` take precedence over ones found by
+/// scanning; if two library files end up with the same name, the first one wins.
+pub fn scan(cfg: &mut Config) -> Result<(), Error> {
+    for lib in cfg.library.iter() {
+        let mut found = vec![];
+        scan_dir(lib, Path::new(&lib.path), Path::new(""), &mut HashSet::new(), &mut found)?;
+        debug!("Library directory '{}': {} files", lib.path, found.len());
+        for (name, pf) in found {
+            if let Some(existing) = cfg.files.get(&name) {
+                if existing.uri != pf.uri {
+                    warn!("Not adding '{}' from the library as '{}': that name is already used by '{}'", pf.uri, name, existing.uri);
+                }
+                continue;
+            }
+            cfg.files.insert(name, pf);
+        }
+    }
+    Ok(())
+}
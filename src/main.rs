@@ -8,79 +8,253 @@ extern crate config as cfg;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate uuid;
+extern crate tiny_http;
+extern crate jack;
+extern crate signal_hook;
+extern crate regex;
+#[macro_use] extern crate serde_json;
 
 pub mod config;
+pub mod cli;
 pub mod ctxt;
 pub mod osc;
+pub mod auth;
+pub mod pattern;
+pub mod tcp;
+pub mod unix;
+pub mod midi;
+pub mod rt;
+pub mod timecode;
+pub mod actions;
+pub mod markers;
+pub mod library;
+pub mod defined;
+pub mod engine;
+pub mod check;
+pub mod http;
+pub mod oscquery;
 
-use sqa_engine::EngineContext;
-use sqa_ffmpeg::MediaFile;
-use std::net::UdpSocket;
-use std::sync::mpsc;
+use std::net::TcpListener;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
 
+/// Prints the configured files, for `--list`.
+fn list_files(cfg: &config::Config) {
+    let mut names = cfg.files.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let pf = &cfg.files[name];
+        println!("{}\t{}\t{}", name, pf.uri, if pf.looping { "looping" } else { "-" });
+    }
+}
+
 fn main() {
     env_logger::init();
+    let opts = match cli::Options::parse(::std::env::args().skip(1)) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            ::std::process::exit(2);
+        }
+    };
+    if opts.mode == cli::Mode::Help {
+        println!("{}", cli::USAGE);
+        return;
+    }
     info!("[+] mfl-gramophone starting up");
     info!("[+] Loading configuration");
-    let cfg = config::Config::get().unwrap();
-    if cfg.channels.len() == 0 {
+    let mut cfg = match opts.load_config() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("error: failed loading config '{}': {}", opts.config, e);
+            ::std::process::exit(1);
+        }
+    };
+    let defined = match cfg.defined_files {
+        Some(ref path) => defined::load(path).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            ::std::process::exit(1);
+        }),
+        None => HashMap::new()
+    };
+    defined::apply(&mut cfg, &defined);
+    if opts.mode == cli::Mode::List {
+        list_files(&cfg);
+        return;
+    }
+    if cfg.channels.is_empty() {
         panic!("No channels configured.");
     }
-    if cfg.files.len() == 0 {
+    if cfg.files.is_empty() {
         warn!("No files configured!");
     }
-    info!("[+] Initialising SQA Engine");
-    let mut ec = EngineContext::new(Some("mfl-gramophone")).unwrap();
-    if ec.conn.sample_rate() as u64 != cfg.sample_rate {
-        panic!("JACK sample rate ({}) doesn't match configured ({})", ec.conn.sample_rate(), cfg.sample_rate);
-    }
-    let (tx, rx) = mpsc::channel();
-    let mut hdl = ec.get_handle().unwrap();
-    let txc = tx.clone();
-    ::std::thread::spawn(move || {
-        loop {
-            let msg = hdl.recv();
-            txc.send(ctxt::Message::Engine(msg)).unwrap();
-        }
-    });
-    let mut chans = vec![];
-    for (i, ch) in cfg.channels.iter().enumerate() {
-        info!("[+] Setting up channel {} (-> {})...", i, ch);
-        let st = format!("channel {}", i);
-        let p = ec.new_channel(&st).expect("making channel failed");
-        let port = ec.conn.get_port_by_name(&ch).expect("getting port failed");
-        ec.conn.connect_ports(ec.chans.get(p).unwrap().as_ref().unwrap(), &port)
-            .expect("patching failed");
-        chans.push(p);
-    }
     info!("[+] Initialising FFmpeg");
     let mut mctx = ::sqa_ffmpeg::init().unwrap();
     info!("[+] Checking configured media files");
-    for (name, pf) in cfg.files.iter() {
-        info!("[+] Checking '{}' ({})...", name, pf.uri);
-        let mf = MediaFile::new(&mut mctx, &pf.uri).expect("failed opening file");
-        if mf.sample_rate() as u64 != cfg.sample_rate {
-            panic!("File '{}' has sample rate {} (needed {})", name, mf.sample_rate(), cfg.sample_rate);
-        }
-        if mf.channels() == 0 {
-            panic!("File '{}' has no channels");
-        }
-        if mf.channels() > cfg.channels.len() {
-            warn!("File '{}' has more channels ({}) than configured ({}); some will not play!", name, mf.channels(), cfg.channels.len());
+    let (file_info, errors) = ctxt::check_files(&mut mctx, &cfg);
+    if opts.mode == cli::Mode::Check {
+        for e in errors.iter() {
+            eprintln!("error: {}", e);
         }
+        println!("{} of {} files OK", file_info.len(), cfg.files.len());
+        ::std::process::exit(if !errors.is_empty() { 1 } else { 0 });
     }
+    if let Some(e) = errors.into_iter().next() {
+        panic!("{}", e);
+    }
+    let (tx, rx) = mpsc::channel();
+    let acl = Arc::new(auth::AccessControl::new(&cfg).expect("invalid access control settings"));
+    let actions = actions::Actions::new(&cfg).expect("invalid actions");
     info!("[+] Initialising OSC");
-    let sock = UdpSocket::bind(&cfg.listen).expect("failed binding socket");
+    let routes = Arc::new(Mutex::new(osc::ReplyRoutes::new(&cfg).expect("invalid reply settings")));
+    let mut osc_addr = None;
+    let osc_stop = Arc::new(AtomicBool::new(false));
+    // Set by the `Context` once the audio engine has started.
+    let jack_up = Arc::new(AtomicBool::new(false));
+    let mut osc_threads = vec![];
+    for spec in cfg.listen.to_vec() {
+        info!("[+] Listening for OSC on {}", spec.addr());
+        let sock = Arc::new(osc::bind_udp(&spec).unwrap_or_else(|e| panic!("{}", e)));
+        if osc_addr.is_none() {
+            osc_addr = Some(sock.local_addr().expect("failed getting socket address"));
+        }
+        let mut osc_ctxt = osc::OscContext {
+            sock,
+            tx: tx.clone(),
+            acl: acl.clone(),
+            jack_up: jack_up.clone(),
+            routes: routes.clone(),
+            stop: osc_stop.clone()
+        };
+        osc_threads.push(::std::thread::spawn(move || {
+            osc_ctxt.run();
+        }));
+    }
+    let osc_addr = osc_addr.expect("No OSC listen addresses configured.");
+    if let Some(ref tcp_listen) = cfg.tcp_listen {
+        info!("[+] Listening for OSC over TCP on {} ({:?} framing)", tcp_listen, cfg.tcp_framing);
+        let listener = TcpListener::bind(tcp_listen).expect("failed binding TCP socket");
+        let mut tcp_ctxt = tcp::TcpContext {
+            listener,
+            framing: cfg.tcp_framing,
+            tx: tx.clone(),
+            acl: acl.clone(),
+            jack_up: jack_up.clone()
+        };
+        ::std::thread::spawn(move || {
+            tcp_ctxt.run();
+        });
+    }
+    if let Some(ref unix_listen) = cfg.unix_listen {
+        info!("[+] Listening on Unix socket {} ({:?})", unix_listen, cfg.unix_kind);
+        let mut unix_ctxt = unix::UnixContext {
+            path: unix_listen.clone(),
+            kind: cfg.unix_kind,
+            mode: cfg.unix_mode.clone(),
+            tx: tx.clone(),
+            acl: acl.clone(),
+            jack_up: jack_up.clone()
+        };
+        ::std::thread::spawn(move || {
+            if let Err(e) = unix_ctxt.run() {
+                error!("Unix socket listener failed: {}", e);
+            }
+        });
+    }
+    if let Some(ref http_listen) = cfg.http_listen {
+        info!("[+] Starting HTTP API on {}", http_listen);
+        let server = tiny_http::Server::http(http_listen as &str).expect("failed starting HTTP server");
+        let mut http_ctxt = http::HttpContext {
+            server,
+            tx: tx.clone(),
+            acl: acl.clone()
+        };
+        ::std::thread::spawn(move || {
+            http_ctxt.run();
+        });
+    }
+    if let Some(ref oscquery_listen) = cfg.oscquery_listen {
+        info!("[+] Starting OSCQuery server on {}", oscquery_listen);
+        let server = tiny_http::Server::http(oscquery_listen as &str).expect("failed starting OSCQuery server");
+        let mut oscquery_ctxt = oscquery::OscQueryContext {
+            server, osc_addr,
+            tx: tx.clone()
+        };
+        ::std::thread::spawn(move || {
+            oscquery_ctxt.run();
+        });
+    }
+    let midi = Arc::new(RwLock::new(midi::MidiTables::new(cfg.midi.as_ref(), &cfg.files)));
+    let chase = cfg.timecode.as_ref().map(|t| {
+        timecode::Chase::new(t, &cfg.files).expect("invalid timecode trigger point")
+    });
+    if chase.is_none() && cfg.files.values().any(|f| f.timecode.is_some()) {
+        warn!("Some files have timecode trigger points, but timecode chasing isn't configured.");
+    }
+    if cfg.timecode_out.is_none() && cfg.files.values().any(|f| f.timecode_out.is_some()) {
+        warn!("Some files have timecode output, but [timecode_out] isn't configured.");
+    }
+    let signals = signal_hook::iterator::Signals::new([signal_hook::SIGHUP, signal_hook::SIGINT, signal_hook::SIGTERM])
+        .expect("failed setting up signal handler");
     let txc = tx.clone();
-    let mut osc_ctxt = osc::OscContext { sock, tx: txc };
     ::std::thread::spawn(move || {
-        osc_ctxt.run();
+        for sig in signals.forever() {
+            let msg = if sig == signal_hook::SIGHUP {
+                info!("Received SIGHUP; reloading");
+                ctxt::Message::Reload(osc::Replier::Discard {
+                    name: "SIGHUP".into()
+                })
+            }
+            else {
+                info!("Received signal {}; shutting down", sig);
+                ctxt::Message::Shutdown
+            };
+            if txc.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    let txc = tx.clone();
+    ::std::thread::spawn(move || {
+        loop {
+            ::std::thread::sleep(::std::time::Duration::from_millis(ctxt::TICK_MS));
+            if txc.send(ctxt::Message::Tick).is_err() {
+                break;
+            }
+        }
     });
     let mut ctx = ctxt::Context { 
-        rx, ec, mctx, cfg, tx,
+        rx, mctx, cfg, file_info, acl, routes, chase, actions, opts, defined, midi,
+        patches: HashMap::new(),
+        checker: check::spawn(tx.clone()),
+        tx,
+        // These all get started by `Context::run`, and again whenever JACK comes back.
+        engine: None,
+        jack_up,
+        midi_in: None,
+        timecode_in: None,
+        timecode_out: None,
         epoch: 0,
-        active_files: HashMap::new()
+        xruns: 0,
+        subscribers: vec![],
+        active_files: HashMap::new(),
+        shutdown_at: None,
+        reconnect_at: None,
+        reconnect_error: None
     };
     ctx.run();
+    info!("[+] Stopping OSC");
+    osc_stop.store(true, Ordering::SeqCst);
+    for thread in osc_threads {
+        if thread.join().is_err() {
+            warn!("OSC thread panicked");
+        }
+    }
+    info!("[+] Stopping MIDI and timecode");
+    ctx.midi_in = None;
+    ctx.timecode_in = None;
+    info!("[+] Deregistering from JACK");
+    drop(ctx);
+    info!("[+] mfl-gramophone shut down");
 }
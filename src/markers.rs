@@ -0,0 +1,220 @@
+//! Markers: named points in files, which fire events when playback reaches them.
+//!
+//! As well as being listed in the config file, markers can be read from the
+//! `cue ` chunk of a WAV file (named using the labels in its `LIST`/`adtl`
+//! chunk, if there are any).
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use config::PlaybackFile;
+use failure::Error;
+
+fn u32_le(buf: &[u8], at: usize) -> Option<u32> {
+    buf.get(at..at + 4)
+        .map(|b| (b[0] as u32) | ((b[1] as u32) << 8) | ((b[2] as u32) << 16) | ((b[3] as u32) << 24))
+}
+/// Splits the contents of a RIFF chunk (that's been read into memory) up into
+/// its subchunks.
+fn subchunks(mut buf: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut ret = vec![];
+    while buf.len() >= 8 {
+        let id = &buf[0..4];
+        let len = u32_le(buf, 4).unwrap() as usize;
+        let end = ::std::cmp::min(8 + len, buf.len());
+        ret.push((id, &buf[8..end]));
+        // Chunks are padded to an even length.
+        let next = ::std::cmp::min(end + (len & 1), buf.len());
+        buf = &buf[next..];
+    }
+    ret
+}
+/// Reads up to `n` bytes from `file`, stopping early at the end of the file.
+fn read_up_to(file: &mut File, n: u64) -> io::Result<Vec<u8>> {
+    let mut ret = vec![];
+    file.take(n).read_to_end(&mut ret)?;
+    Ok(ret)
+}
+/// Reads the top-level chunks of the WAV file `file` that markers come from,
+/// skipping over the rest (like the audio itself) without reading them.
+fn marker_chunks(file: &mut File) -> io::Result<Vec<([u8; 4], Vec<u8>)>> {
+    let mut ret = vec![];
+    loop {
+        let header = read_up_to(file, 8)?;
+        if header.len() < 8 {
+            break;
+        }
+        let mut id = [0; 4];
+        id.copy_from_slice(&header[0..4]);
+        let len = u32_le(&header, 4).unwrap() as u64;
+        // Chunks are padded to an even length.
+        let padded = len + (len & 1);
+        match &id {
+            b"fmt " | b"cue " | b"LIST" => {
+                let data = read_up_to(file, len)?;
+                let truncated = (data.len() as u64) < len;
+                ret.push((id, data));
+                if truncated {
+                    break;
+                }
+                file.seek(SeekFrom::Current((padded - len) as i64))?;
+            },
+            _ => {
+                file.seek(SeekFrom::Current(padded as i64))?;
+            }
+        }
+    }
+    Ok(ret)
+}
+/// Reads the markers from the WAV file at `path`, as (name, position in
+/// milliseconds) pairs.
+pub fn read_wav_markers(path: &str) -> Result<Vec<(String, u64)>, Error> {
+    let mut file = File::open(path)
+        .map_err(|e| format_err!("failed opening '{}' to read markers: {}", path, e))?;
+    let header = read_up_to(&mut file, 12)?;
+    if header.len() < 12 || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        bail!("'{}' isn't a WAV file, so markers can't be read from it", path);
+    }
+    let mut sample_rate = None;
+    let mut positions = vec![];
+    let mut labels = HashMap::new();
+    for (id, data) in marker_chunks(&mut file)? {
+        let data = &data as &[u8];
+        match &id {
+            b"fmt " => {
+                sample_rate = u32_le(data, 4);
+            },
+            b"cue " => {
+                // Don't trust the count any further than the chunk goes.
+                let count = u32_le(data, 0).unwrap_or(0) as usize;
+                let count = count.min(data.len().saturating_sub(4) / 24);
+                for i in 0..count {
+                    let base = 4 + i * 24;
+                    if let (Some(id), Some(offset)) = (u32_le(data, base), u32_le(data, base + 20)) {
+                        positions.push((id, offset));
+                    }
+                }
+            },
+            b"LIST" if data.get(0..4) == Some(&b"adtl"[..]) => {
+                for (id, data) in subchunks(&data[4..]) {
+                    if id != b"labl" {
+                        continue;
+                    }
+                    if let Some(cue) = u32_le(data, 0) {
+                        let text = data[4..].split(|&b| b == 0).next().unwrap_or(&[]);
+                        labels.insert(cue, String::from_utf8_lossy(text).into_owned());
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+    let sample_rate = sample_rate
+        .ok_or(format_err!("'{}' has no fmt chunk", path))? as u64;
+    if sample_rate == 0 {
+        bail!("'{}' has a sample rate of 0", path);
+    }
+    Ok(positions.into_iter()
+        .map(|(id, offset)| {
+            let name = labels.remove(&id).unwrap_or(format!("cue{}", id));
+            (name, offset as u64 * 1000 / sample_rate)
+        })
+        .collect())
+}
+/// Works out every marker for `pf`, sorted by position.
+pub fn file_markers(name: &str, pf: &PlaybackFile) -> Result<Vec<(String, u64)>, Error> {
+    let mut ret = pf.markers.iter()
+        .map(|m| (m.name.clone(), m.at_ms))
+        .collect::<Vec<_>>();
+    if pf.wav_markers {
+        let wav = read_wav_markers(&pf.uri)
+            .map_err(|e| format_err!("file '{}': {}", name, e))?;
+        ret.extend(wav);
+    }
+    ret.sort_by_key(|&(_, at)| at);
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut ret = id.to_vec();
+        let len = data.len() as u32;
+        ret.extend(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+        ret.extend(data);
+        if data.len() % 2 == 1 {
+            ret.push(0);
+        }
+        ret
+    }
+    fn le(n: u32) -> [u8; 4] {
+        [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+    }
+    /// Makes a `cue ` chunk, claiming to have `count` cue points, out of
+    /// (ID, sample offset) pairs.
+    fn cue_chunk(count: u32, cues: &[(u32, u32)]) -> Vec<u8> {
+        let mut data = le(count).to_vec();
+        for &(id, offset) in cues.iter() {
+            data.extend(&le(id));
+            // Position, data chunk ID, chunk start, block start.
+            data.extend(&le(0));
+            data.extend(b"data");
+            data.extend(&le(0));
+            data.extend(&le(0));
+            data.extend(&le(offset));
+        }
+        chunk(b"cue ", &data)
+    }
+    /// Writes a WAV file made of `chunks` somewhere temporary, and reads its markers.
+    fn markers_of(test: &str, chunks: &[Vec<u8>]) -> Result<Vec<(String, u64)>, Error> {
+        let mut body = b"WAVE".to_vec();
+        for c in chunks.iter() {
+            body.extend(c);
+        }
+        let wav = chunk(b"RIFF", &body);
+        let path = ::std::env::temp_dir()
+            .join(format!("mfl-gramophone-{}-{}.wav", test, ::std::process::id()));
+        File::create(&path).unwrap().write_all(&wav).unwrap();
+        let ret = read_wav_markers(&path.to_string_lossy());
+        let _ = ::std::fs::remove_file(&path);
+        ret
+    }
+    fn fmt_chunk(sample_rate: u32) -> Vec<u8> {
+        let mut data = vec![1, 0, 1, 0];
+        data.extend(&le(sample_rate));
+        data.extend(&le(sample_rate * 2));
+        data.extend(&[2, 0, 16, 0]);
+        chunk(b"fmt ", &data)
+    }
+
+    #[test]
+    fn reads_cues_and_labels() {
+        let mut labl = le(1).to_vec();
+        labl.extend(b"intro\0");
+        let mut adtl = b"adtl".to_vec();
+        adtl.extend(chunk(b"labl", &labl));
+        let markers = markers_of("cues", &[
+            fmt_chunk(48000),
+            // An odd-length chunk, to check that padding gets skipped.
+            chunk(b"data", &[0, 0, 0]),
+            cue_chunk(2, &[(1, 48000), (2, 24000)]),
+            chunk(b"LIST", &adtl)
+        ]).unwrap();
+        assert_eq!(markers, vec![("intro".to_string(), 1000), ("cue2".to_string(), 500)]);
+    }
+    #[test]
+    fn clamps_cue_count() {
+        let markers = markers_of("count", &[
+            fmt_chunk(1000),
+            cue_chunk(0xFFFFFFFF, &[(7, 250)])
+        ]).unwrap();
+        assert_eq!(markers, vec![("cue7".to_string(), 250)]);
+    }
+    #[test]
+    fn needs_fmt() {
+        assert!(markers_of("nofmt", &[cue_chunk(1, &[(1, 10)])]).is_err());
+    }
+}
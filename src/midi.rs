@@ -0,0 +1,448 @@
+//! Triggering commands from JACK MIDI input, including MIDI Show Control.
+//!
+//! sqa-engine's JACK connection only deals in audio ports (and owns the process
+//! callback), so MIDI gets its own JACK client.
+
+use std::sync::mpsc::{Sender, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use std::collections::HashMap;
+use jack::{self, Client, ClientOptions, ProcessScope, Control, Port, MidiIn, AsyncClient};
+use rosc::{OscMessage, OscType};
+use ctxt::Message;
+use config::{MidiConfig, MidiBinding, MidiEventKind, MscConfig, ConfigArg, PlaybackFile};
+use osc::{self, Replier};
+use auth::AccessControl;
+use rt::{self, RtMessage, RtSender, RtReceiver};
+use failure::Error;
+
+/// The name of the JACK client used for MIDI.
+pub const CLIENT_NAME: &str = "mfl-gramophone-midi";
+
+/// How many MIDI messages can be waiting for the worker thread at once.
+const QUEUE_LEN: usize = 256;
+
+/// A raw MIDI message, as passed from the JACK process callback to the worker
+/// thread.
+///
+/// Messages longer than this (which can only be SysEx, and much longer than MSC
+/// messages get) are dropped.
+struct RawMessage {
+    len: usize,
+    bytes: [u8; rt::MAX_SIZE - 1]
+}
+impl RawMessage {
+    fn new(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > rt::MAX_SIZE - 1 {
+            return None;
+        }
+        let mut ret = RawMessage {
+            len: bytes.len(),
+            bytes: [0; rt::MAX_SIZE - 1]
+        };
+        ret.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(ret)
+    }
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+impl RtMessage for RawMessage {
+    const SIZE: usize = rt::MAX_SIZE;
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.len as u8;
+        buf[1..].copy_from_slice(&self.bytes);
+    }
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let len = buf[0] as usize;
+        RawMessage::new(buf.get(1..len + 1)?)
+    }
+}
+/// Forwards raw MIDI messages from the JACK process callback to the worker thread.
+pub struct MidiProcess {
+    port: Port<MidiIn>,
+    tx: RtSender<RawMessage>
+}
+impl jack::ProcessHandler for MidiProcess {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        for ev in self.port.iter(ps) {
+            if let Some(msg) = RawMessage::new(ev.bytes) {
+                self.tx.send(&msg);
+            }
+        }
+        Control::Continue
+    }
+}
+/// A running MIDI input. Dropping this deactivates the JACK client.
+pub struct MidiInput {
+    _client: AsyncClient<(), MidiProcess>
+}
+
+/// A MIDI message we know how to bind to.
+#[derive(Copy, Clone, Debug)]
+struct MidiEvent {
+    kind: MidiEventKind,
+    /// 1-16.
+    channel: u8,
+    number: u8,
+    /// Note velocity, CC value, or 127 for program changes.
+    value: u8,
+    /// False for note-offs.
+    on: bool
+}
+impl MidiEvent {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 2 {
+            return None;
+        }
+        let channel = (bytes[0] & 0x0F) + 1;
+        let (kind, value, on) = match (bytes[0] & 0xF0, bytes.get(2)) {
+            (0x90, Some(&vel)) => (MidiEventKind::Note, vel, vel > 0),
+            (0x80, Some(&vel)) => (MidiEventKind::Note, vel, false),
+            (0xB0, Some(&val)) => (MidiEventKind::Cc, val, true),
+            (0xC0, _) => (MidiEventKind::Program, 127, true),
+            _ => return None
+        };
+        Some(MidiEvent {
+            kind, channel, value, on,
+            number: bytes[1]
+        })
+    }
+}
+
+/// The MIDI Show Control commands we respond to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum MscCommand {
+    Go,
+    Stop,
+    Resume,
+    Load,
+    AllOff,
+    Reset
+}
+/// A MIDI Show Control message.
+#[derive(Clone, Debug)]
+struct MscMessage {
+    device_id: u8,
+    command_format: u8,
+    command: MscCommand,
+    /// The cue number, if one was given.
+    cue: Option<String>
+}
+impl MscMessage {
+    /// Parses an MSC SysEx message (`F0 7F DEVICE 02 FORMAT COMMAND DATA... F7`).
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 7 || bytes[0] != 0xF0 || bytes[1] != 0x7F || bytes[3] != 0x02 {
+            return None;
+        }
+        if bytes[bytes.len() - 1] != 0xF7 {
+            return None;
+        }
+        let command = match bytes[5] {
+            0x01 => MscCommand::Go,
+            0x02 => MscCommand::Stop,
+            0x03 => MscCommand::Resume,
+            0x05 => MscCommand::Load,
+            0x08 => MscCommand::AllOff,
+            0x0A => MscCommand::Reset,
+            _ => return None
+        };
+        // The data is the cue number, then optionally the cue list and path,
+        // separated by zero bytes.
+        let data = &bytes[6..bytes.len() - 1];
+        let cue = data.split(|&b| b == 0).next()
+            .and_then(|c| if !c.is_empty() { Some(String::from_utf8_lossy(c).into_owned()) } else { None });
+        Some(MscMessage {
+            device_id: bytes[2],
+            command_format: bytes[4],
+            command, cue
+        })
+    }
+    /// Whether this message is meant for us.
+    fn is_for(&self, cfg: &MscConfig) -> bool {
+        let device = self.device_id == 0x7F
+            || self.device_id == cfg.device_id
+            || cfg.groups.contains(&self.device_id);
+        let format = self.command_format == 0x7F
+            || cfg.command_formats.is_empty()
+            || cfg.command_formats.contains(&self.command_format);
+        device && format
+    }
+}
+
+/// What incoming MIDI messages do.
+///
+/// This is shared with the `Context`, which updates it as the config is
+/// reloaded and files come and go.
+pub struct MidiTables {
+    pub bindings: Vec<MidiBinding>,
+    pub msc: Option<MscConfig>,
+    /// MSC cue numbers, and the files they trigger.
+    pub cues: Vec<(String, String)>
+}
+impl MidiTables {
+    pub fn new(cfg: Option<&MidiConfig>, files: &HashMap<String, PlaybackFile>) -> Self {
+        MidiTables {
+            bindings: cfg.map(|c| c.bindings.clone()).unwrap_or(vec![]),
+            msc: cfg.and_then(|c| c.msc.clone()),
+            cues: Self::cue_table(files)
+        }
+    }
+    /// Works out which files each MSC cue number triggers.
+    pub fn cue_table(files: &HashMap<String, PlaybackFile>) -> Vec<(String, String)> {
+        let mut cues = files.iter()
+            .filter_map(|(name, pf)| pf.cue.as_ref().map(|c| (c.clone(), name.clone())))
+            .collect::<Vec<_>>();
+        cues.sort();
+        cues
+    }
+}
+struct MidiWorker {
+    tables: Arc<RwLock<MidiTables>>,
+    tx: Sender<Message>,
+    acl: AccessControl,
+    jack_up: Arc<AtomicBool>,
+    reply: Replier
+}
+impl MidiWorker {
+    /// Converts a velocity to a level, in decibels.
+    fn velocity_db(b: &MidiBinding, value: u8) -> f64 {
+        let frac = (value.max(1) - 1) as f64 / 126.0;
+        b.velocity_min_db * (1.0 - frac)
+    }
+    fn fire(&self, command: &str, args: &[ConfigArg], level: Option<f64>) {
+        let mut args = args.iter().map(|a| a.to_osc()).collect::<Vec<_>>();
+        if let Some(level) = level {
+            if !args.is_empty() {
+                args[0] = OscType::Float(level as f32);
+            }
+            else {
+                args.push(OscType::Float(level as f32));
+            }
+        }
+        let msg = OscMessage {
+            addr: command.to_string(),
+            args: if !args.is_empty() { Some(args) } else { None }
+        };
+        osc::process_msg(&self.tx, &self.acl, &self.jack_up, msg, &self.reply);
+    }
+    fn handle(&self, bindings: &[MidiBinding], ev: MidiEvent) {
+        for b in bindings.iter() {
+            if b.kind != ev.kind || b.number != ev.number {
+                continue;
+            }
+            if b.channel.map(|c| c != ev.channel).unwrap_or(false) {
+                continue;
+            }
+            let on = match ev.kind {
+                MidiEventKind::Cc => b.velocity_level || ev.value > 0,
+                _ => ev.on
+            };
+            if on {
+                let level = if b.velocity_level {
+                    Some(Self::velocity_db(b, ev.value))
+                }
+                else {
+                    None
+                };
+                self.fire(&b.command, &b.args, level);
+            }
+            else if let Some(ref off) = b.off_command {
+                self.fire(off, &b.off_args, None);
+            }
+        }
+    }
+    /// Calls `func` with the name of every file triggered by MSC cue `cue`.
+    fn for_each_cue<F: FnMut(String) -> Message>(&self, cues: &[(String, String)], cue: &str, mut func: F) {
+        let mut found = false;
+        for (c, name) in cues.iter() {
+            if c == cue {
+                found = true;
+                self.tx.send(func(name.clone())).unwrap();
+            }
+        }
+        if !found {
+            info!("No files for MSC cue {}", cue);
+        }
+    }
+    fn handle_msc(&self, cfg: &MscConfig, cues: &[(String, String)], msg: MscMessage) {
+        use self::MscCommand::*;
+
+        if !msg.is_for(cfg) {
+            debug!("Ignoring MSC message for someone else: {:?}", msg);
+            return;
+        }
+        info!("MSC {:?}, cue {}", msg.command, msg.cue.as_ref().map(|x| x as &str).unwrap_or("(none)"));
+        let level = cfg.level;
+        match (msg.command, msg.cue) {
+            // There's no pause, so RESUME just starts things again.
+            (Go, Some(cue)) | (Resume, Some(cue)) => {
+                self.for_each_cue(cues, &cue, |name| Message::PlayFile(name, Some(level)));
+            },
+            (Load, Some(cue)) => {
+                self.for_each_cue(cues, &cue, |name| Message::LoadFile(name, Some(level)));
+            },
+            (Stop, Some(cue)) => {
+                self.for_each_cue(cues, &cue, Message::StopFile);
+            },
+            (Stop, None) | (AllOff, _) | (Reset, _) => {
+                self.tx.send(Message::StopFile("*".into())).unwrap();
+            },
+            (cmd, None) => {
+                warn!("MSC {:?} without a cue number isn't supported", cmd);
+            }
+        }
+    }
+    fn run(&mut self, mut rx: RtReceiver<RawMessage>) {
+        loop {
+            let msg = match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(m) => m,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break
+            };
+            let bytes = msg.bytes();
+            let tables = self.tables.read().unwrap();
+            if let Some(ref cfg) = tables.msc {
+                if let Some(msg) = MscMessage::parse(bytes) {
+                    self.handle_msc(cfg, &tables.cues, msg);
+                    continue;
+                }
+            }
+            if let Some(ev) = MidiEvent::parse(bytes) {
+                debug!("MIDI event: {:?}", ev);
+                self.handle(&tables.bindings, ev);
+            }
+        }
+    }
+}
+
+/// Creates the JACK MIDI input, connects it up, and starts handling events
+/// according to `tables`.
+pub fn start(cfg: &MidiConfig, tables: Arc<RwLock<MidiTables>>, tx: Sender<Message>, jack_up: Arc<AtomicBool>) -> Result<MidiInput, Error> {
+    let (client, _) = Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER)
+        .map_err(|e| format_err!("failed creating JACK client: {:?}", e))?;
+    let port = client.register_port("midi_in", MidiIn)
+        .map_err(|e| format_err!("failed registering MIDI port: {:?}", e))?;
+    let port_name = port.name()
+        .map_err(|e| format_err!("failed getting MIDI port name: {:?}", e))?;
+    let (mtx, mrx) = rt::channel(QUEUE_LEN)?;
+    let client = client.activate_async((), MidiProcess { port, tx: mtx })
+        .map_err(|e| format_err!("failed activating JACK client: {:?}", e))?;
+    for src in cfg.connect.iter() {
+        info!("[+] Connecting MIDI input to {}", src);
+        if let Err(e) = client.as_client().connect_ports_by_name(src, &port_name) {
+            warn!("Failed connecting MIDI input to {}: {:?}", src, e);
+        }
+    }
+    let mut worker = MidiWorker {
+        tables,
+        tx,
+        acl: AccessControl::unrestricted(),
+        jack_up,
+        reply: Replier::Discard {
+            name: "midi".into()
+        }
+    };
+    ::std::thread::spawn(move || {
+        worker.run(mrx);
+    });
+    Ok(MidiInput {
+        _client: client
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msc(device_id: u8, format: u8, command: u8, data: &[u8]) -> Vec<u8> {
+        let mut ret = vec![0xF0, 0x7F, device_id, 0x02, format, command];
+        ret.extend_from_slice(data);
+        ret.push(0xF7);
+        ret
+    }
+    fn cfg(device_id: u8, groups: Vec<u8>, command_formats: Vec<u8>) -> MscConfig {
+        MscConfig { device_id, groups, command_formats, level: 0.0 }
+    }
+
+    #[test]
+    fn msc_parse() {
+        let m = MscMessage::parse(&msc(3, 0x10, 0x01, b"12.5\x0012\x00")).unwrap();
+        assert_eq!(m.device_id, 3);
+        assert_eq!(m.command_format, 0x10);
+        assert_eq!(m.command, MscCommand::Go);
+        assert_eq!(m.cue, Some("12.5".into()));
+        let m = MscMessage::parse(&msc(3, 0x10, 0x02, b"7")).unwrap();
+        assert_eq!(m.command, MscCommand::Stop);
+        assert_eq!(m.cue, Some("7".into()));
+        let m = MscMessage::parse(&msc(3, 0x10, 0x0A, b"")).unwrap();
+        assert_eq!(m.command, MscCommand::Reset);
+        assert_eq!(m.cue, None);
+        // A cue list, but no cue number.
+        let m = MscMessage::parse(&msc(3, 0x10, 0x03, b"\x002")).unwrap();
+        assert_eq!(m.cue, None);
+    }
+    #[test]
+    fn msc_parse_invalid() {
+        // Unknown command.
+        assert!(MscMessage::parse(&msc(3, 0x10, 0x04, b"1")).is_none());
+        // Not MSC (sub-ID 1 is MTC), or not universal real-time.
+        let mut bytes = msc(3, 0x10, 0x01, b"1");
+        bytes[3] = 0x01;
+        assert!(MscMessage::parse(&bytes).is_none());
+        let mut bytes = msc(3, 0x10, 0x01, b"1");
+        bytes[1] = 0x7E;
+        assert!(MscMessage::parse(&bytes).is_none());
+        // Unterminated, or too short.
+        let mut bytes = msc(3, 0x10, 0x01, b"1");
+        bytes.pop();
+        assert!(MscMessage::parse(&bytes).is_none());
+        assert!(MscMessage::parse(&[0xF0, 0x7F, 3, 0x02, 0x10, 0xF7]).is_none());
+        assert!(MscMessage::parse(&[0x90, 60, 100]).is_none());
+    }
+    #[test]
+    fn msc_device_id() {
+        let cfg = cfg(3, vec![0x70, 0x72], vec![]);
+        let is_for = |device_id| MscMessage::parse(&msc(device_id, 0x10, 0x01, b"1")).unwrap().is_for(&cfg);
+        assert!(is_for(3));
+        assert!(!is_for(4));
+        // The all-call ID.
+        assert!(is_for(0x7F));
+        // Groups we're in, and ones we aren't.
+        assert!(is_for(0x70));
+        assert!(is_for(0x72));
+        assert!(!is_for(0x71));
+    }
+    #[test]
+    fn msc_command_format() {
+        let is_for = |cfg: &MscConfig, format| MscMessage::parse(&msc(3, format, 0x01, b"1")).unwrap().is_for(cfg);
+        let any = cfg(3, vec![], vec![]);
+        assert!(is_for(&any, 0x10));
+        assert!(is_for(&any, 0x01));
+        let sound = cfg(3, vec![], vec![0x10, 0x11]);
+        assert!(is_for(&sound, 0x10));
+        assert!(is_for(&sound, 0x11));
+        assert!(!is_for(&sound, 0x01));
+        // "All types" is always for us.
+        assert!(is_for(&sound, 0x7F));
+        // Both the device and the format have to match.
+        let other = cfg(4, vec![], vec![0x10]);
+        assert!(!is_for(&other, 0x10));
+    }
+    #[test]
+    fn midi_event_parse() {
+        let ev = MidiEvent::parse(&[0x92, 60, 100]).unwrap();
+        assert_eq!((ev.kind, ev.channel, ev.number, ev.value, ev.on), (MidiEventKind::Note, 3, 60, 100, true));
+        // A note-on with zero velocity is a note-off.
+        assert!(!MidiEvent::parse(&[0x92, 60, 0]).unwrap().on);
+        assert!(!MidiEvent::parse(&[0x82, 60, 64]).unwrap().on);
+        let ev = MidiEvent::parse(&[0xBF, 7, 90]).unwrap();
+        assert_eq!((ev.kind, ev.channel, ev.number, ev.value), (MidiEventKind::Cc, 16, 7, 90));
+        let ev = MidiEvent::parse(&[0xC0, 5]).unwrap();
+        assert_eq!((ev.kind, ev.channel, ev.number, ev.value), (MidiEventKind::Program, 1, 5, 127));
+        assert!(MidiEvent::parse(&[0x90, 60]).is_none());
+        assert!(MidiEvent::parse(&[0xE0, 0, 64]).is_none());
+        assert!(MidiEvent::parse(&[0xF8]).is_none());
+    }
+}
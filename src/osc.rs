@@ -1,153 +1,777 @@
-use std::net::{UdpSocket, ToSocketAddrs};
+use std::net::{UdpSocket, SocketAddr, IpAddr, ToSocketAddrs};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::fmt;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use std::sync::mpsc::Sender;
 use rosc::{OscMessage, OscPacket, OscType, self};
-use ctxt::Message;
+use ctxt::{Message, Query};
+use config::{Config, DefinedFile, Framing, Listen};
 use failure::Error;
+use auth::AccessControl;
+use tcp;
+use unix;
 
-pub struct OscContext {
-    pub sock: UdpSocket,
-    pub tx: Sender<Message>,
+/// How long, in milliseconds, writing a reply to a stream connection can take
+/// before we give up on it, so that a client that's stopped reading can't hold
+/// everything else up.
+pub const STREAM_WRITE_TIMEOUT_MS: u64 = 500;
+
+/// Stands in for a stream connection after writing to it has failed, so that
+/// nothing more gets written to it (possibly halfway through a frame).
+struct BrokenStream;
+impl Write for BrokenStream {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(ErrorKind::BrokenPipe, "an earlier write failed"))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
-impl OscContext {
-    fn parse_message(addr: &[&str], args: Option<Vec<OscType>>) -> Option<Message> {
-        if addr.len() == 1 {
+/// The most connections (over TCP, a Unix socket, or HTTP) handled at once by
+/// each listener; any more get turned away, so that clients can't make us start
+/// any number of threads.
+pub const MAX_CONNECTIONS: usize = 64;
+/// Counts the connections a listener is handling.
+#[derive(Clone, Default)]
+pub struct ConnectionCount(Arc<AtomicUsize>);
+impl ConnectionCount {
+    /// Counts a new connection, until the returned guard is dropped; returns
+    /// `None` if there are already `MAX_CONNECTIONS`.
+    pub fn acquire(&self) -> Option<ConnectionGuard> {
+        if self.0.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            self.0.fetch_sub(1, Ordering::SeqCst);
             return None;
         }
-        match addr[1] {
-            "ping" => {
-                Some(Message::Ping)
+        Some(ConnectionGuard(self.0.clone()))
+    }
+}
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Where a UDP reply should go, as configured by `reply_routes`.
+#[derive(Copy, Clone, Debug)]
+enum ReplyDest {
+    /// A fixed address.
+    Addr(SocketAddr),
+    /// The same host the command came from, but on this port.
+    Port(u16)
+}
+impl ReplyDest {
+    fn parse(s: &str) -> Result<Self, Error> {
+        if let Ok(port) = s.trim().parse::<u16>() {
+            return Ok(ReplyDest::Port(port));
+        }
+        let addr = s.to_socket_addrs()
+            .map_err(|e| format_err!("invalid reply destination '{}': {}", s, e))?
+            .next()
+            .ok_or(format_err!("reply destination '{}' didn't resolve", s))?;
+        Ok(ReplyDest::Addr(addr))
+    }
+    fn resolve(&self, from: SocketAddr) -> SocketAddr {
+        match *self {
+            ReplyDest::Addr(a) => a,
+            ReplyDest::Port(p) => SocketAddr::new(from.ip(), p)
+        }
+    }
+}
+/// Decides where UDP replies should be sent.
+///
+/// In order of precedence, replies go to: the port set with `/reply_port`; the
+/// per-source destination in `reply_routes`; the global `reply_to` destination;
+/// and, failing all of those, wherever the command came from.
+pub struct ReplyRoutes {
+    global: Option<ReplyDest>,
+    per_source: HashMap<IpAddr, ReplyDest>,
+    ports: HashMap<IpAddr, u16>
+}
+impl ReplyRoutes {
+    pub fn new(cfg: &Config) -> Result<Self, Error> {
+        let global = match cfg.reply_to {
+            Some(ref r) => Some(ReplyDest::parse(r)?),
+            None => None
+        };
+        let mut per_source = HashMap::new();
+        for (src, dest) in cfg.reply_routes.iter() {
+            let src: IpAddr = src.parse()
+                .map_err(|e| format_err!("invalid source address '{}': {}", src, e))?;
+            per_source.insert(src, ReplyDest::parse(dest)?);
+        }
+        Ok(ReplyRoutes {
+            global, per_source,
+            ports: HashMap::new()
+        })
+    }
+    /// Works out where replies to a command from `from` should go.
+    pub fn destination(&self, from: SocketAddr) -> SocketAddr {
+        if let Some(&port) = self.ports.get(&from.ip()) {
+            return SocketAddr::new(from.ip(), port);
+        }
+        if let Some(dest) = self.per_source.get(&from.ip()) {
+            return dest.resolve(from);
+        }
+        if let Some(dest) = self.global {
+            return dest.resolve(from);
+        }
+        from
+    }
+    /// Switches over to the destinations in `new` (after the config has been
+    /// reloaded), keeping any ports set with `/reply_port`.
+    pub fn replace(&mut self, new: ReplyRoutes) {
+        self.global = new.global;
+        self.per_source = new.per_source;
+    }
+    /// Makes replies to commands from `from`'s host go to `port` (or back to the
+    /// source port, if `port` is 0).
+    pub fn set_port(&mut self, from: IpAddr, port: u16) {
+        if port == 0 {
+            self.ports.remove(&from);
+        }
+        else {
+            self.ports.insert(from, port);
+        }
+    }
+}
+
+/// How replies are written to a stream.
+#[derive(Copy, Clone, Debug)]
+pub enum StreamFormat {
+    /// OSC packets, framed using `Framing`.
+    Osc(Framing),
+    /// One line of text per message (see `unix::format_text`).
+    Text
+}
+
+/// Somewhere to send replies to a command.
+#[derive(Clone)]
+pub enum Replier {
+    /// Reply over the UDP socket `sock` to a command from `addr`, using `routes`
+    /// to work out where the reply should actually go.
+    Udp {
+        sock: Arc<UdpSocket>,
+        addr: SocketAddr,
+        routes: Arc<Mutex<ReplyRoutes>>
+    },
+    /// Reply over a stream connection (e.g. TCP), described by `name`.
+    Stream {
+        stream: Arc<Mutex<Box<dyn Write + Send>>>,
+        format: StreamFormat,
+        name: String
+    },
+    /// Reply to `path` over the Unix datagram socket `sock`.
+    ///
+    /// If the client didn't bind its socket to a path, `path` is `None`, and
+    /// replies get dropped.
+    UnixDatagram {
+        sock: Arc<UnixDatagram>,
+        path: Option<PathBuf>
+    },
+    /// Throw replies away; used for commands that come from inside the server.
+    Discard {
+        name: String
+    },
+    /// Send replies down a channel, to be dealt with by another thread.
+    ///
+    /// `name` identifies the other end (e.g. `http:127.0.0.1:43210`).
+    Channel {
+        tx: Sender<OscMessage>,
+        name: String
+    }
+}
+impl Replier {
+    /// Returns a textual description of where replies go, e.g. `udp:127.0.0.1:53000`.
+    pub fn describe(&self) -> String {
+        match *self {
+            Replier::Udp { addr, .. } => format!("udp:{}", addr),
+            Replier::Stream { ref name, .. } => name.clone(),
+            Replier::UnixDatagram { ref path, .. } => match *path {
+                Some(ref p) => format!("unix:{}", p.display()),
+                None => "unix:(unnamed)".into()
             },
-            "shutdown" => {
-                Some(Message::Shutdown)
+            Replier::Channel { ref name, .. } | Replier::Discard { ref name } => name.clone()
+        }
+    }
+    /// Whether this replier is a connection that can go away (and should be
+    /// forgotten about if sending to it fails).
+    pub fn is_connection(&self) -> bool {
+        match *self {
+            Replier::Udp { .. } | Replier::UnixDatagram { .. } | Replier::Discard { .. } => false,
+            Replier::Stream { .. } | Replier::Channel { .. } => true
+        }
+    }
+    fn encode(msg: OscMessage) -> Result<Vec<u8>, Error> {
+        rosc::encoder::encode(&OscPacket::Message(msg))
+            .map_err(|e| format_err!("{:?}", e))
+    }
+    pub fn try_send(&self, msg: OscMessage) -> Result<(), Error> {
+        match *self {
+            Replier::Udp { ref sock, addr, ref routes } => {
+                let dest = routes.lock().unwrap().destination(addr);
+                sock.send_to(&Self::encode(msg)?, dest)?;
             },
-            "file" => {
-                if addr.len() <= 3 {
-                    return None;
+            Replier::Stream { ref stream, format, .. } => {
+                let buf = match format {
+                    StreamFormat::Osc(framing) => tcp::encode_frame(framing, &Self::encode(msg)?),
+                    StreamFormat::Text => unix::format_text(&msg).into_bytes()
+                };
+                let mut stream = stream.lock().unwrap();
+                if let Err(e) = stream.write_all(&buf).and_then(|_| stream.flush()) {
+                    *stream = Box::new(BrokenStream);
+                    return Err(e.into());
                 }
-                match addr[3] {
-                    "start" => {
-                        if let Some(args) = args {
-                            if args.len() != 1 {
-                                return None;
-                            }
-                            let level: f64;
-                            match args[0] {
-                                OscType::Float(f) => level = f as _,
-                                OscType::Double(f) => level = f as _,
-                                _ => return None
-                            }
-                            Some(Message::PlayFile(addr[2].into(), level))
-                        }
-                        else {
-                            None
-                        }
-                    },
-                    "debug" => {
-                        Some(Message::DebugFile(addr[2].into()))
-                    },
-                    "stop" => {
-                        Some(Message::StopFile(addr[2].into()))
-                    },
-                    "fade" => {
-                        if let Some(args) = args {
-                            if args.len() != 2 {
-                                return None;
-                            }
-                            let dur_ms: u64;
-                            let target: f64;
-                            match args[1] {
-                                OscType::Int(dur) => dur_ms = dur as _,
-                                _ => return None
-                            }
-                            match args[0] {
-                                OscType::Float(f) => target = f as _,
-                                OscType::Double(f) => target = f as _,
-                                _ => return None
-                            }
-                            Some(Message::FadeFile(addr[2].into(), target, dur_ms))
-                        }
-                        else {
-                            None
-                        }
-                    },
-                    _ => {
-                        None
-                    }
+            },
+            Replier::UnixDatagram { ref sock, ref path } => {
+                if let Some(ref p) = *path {
+                    sock.send_to(&Self::encode(msg)?, p)?;
                 }
             },
-            _ => {
-                None
+            Replier::Channel { ref tx, .. } => {
+                tx.send(msg)
+                    .map_err(|_| format_err!("channel closed"))?;
+            },
+            Replier::Discard { .. } => {}
+        }
+        Ok(())
+    }
+    /// Whether `msg` is small enough to be sent through this replier (which it
+    /// isn't if it's bigger than a datagram, and the replier sends datagrams).
+    pub fn fits(&self, msg: &OscMessage) -> bool {
+        match *self {
+            Replier::Udp { .. } | Replier::UnixDatagram { .. } => {
+                Self::encode(msg.clone())
+                    .map(|buf| buf.len() <= rosc::decoder::MTU)
+                    .unwrap_or(true)
+            },
+            _ => true
+        }
+    }
+    pub fn send(&self, msg: OscMessage) {
+        if let Err(e) = self.try_send(msg) {
+            warn!("Failed to send OSC reply to {}: {}", self.describe(), e);
+        }
+    }
+    /// Changes the port replies are sent to, for UDP repliers.
+    pub fn set_reply_port(&self, port: u16) -> Result<(), Error> {
+        match *self {
+            Replier::Udp { addr, ref routes, .. } => {
+                info!("Setting reply port for {} to {}", addr.ip(), port);
+                routes.lock().unwrap().set_port(addr.ip(), port);
+                Ok(())
+            },
+            _ => bail!("reply ports can only be set for UDP")
+        }
+    }
+}
+
+/// Characters that mean something in OSC addresses or patterns, and so can't
+/// be part of a name that's used in one.
+pub const RESERVED_CHARS: &str = " #*,/?[]{}";
+
+/// A command we accept, as described by the OSCQuery namespace.
+pub struct Command {
+    /// The command's address. `{file}`, `{channel}` and `{action}` stand in for
+    /// the name of a file, the number of a channel, and the name of an action.
+    pub addr: &'static str,
+    /// OSC type tags for the command's arguments (empty if it doesn't take any).
+    pub types: &'static str,
+    pub desc: &'static str
+}
+/// Every command `process_msg` accepts.
+pub const COMMANDS: &[Command] = &[
+    Command { addr: "/ping", types: "", desc: "Does nothing, apart from replying with /ack." },
+    Command { addr: "/shutdown", types: "", desc: "Shuts the server down." },
+    Command { addr: "/reload", types: "", desc: "Re-reads the config file, and replies with what changed." },
+    Command { addr: "/debug", types: "", desc: "Replies with debug information about the engine." },
+    Command { addr: "/subscribe", types: "", desc: "Starts sending /event messages to the sender." },
+    Command { addr: "/unsubscribe", types: "", desc: "Stops sending /event messages to the sender." },
+    Command { addr: "/reply_port", types: "i", desc: "Sends replies to the sender's address on port PORT instead (UDP only)." },
+    Command { addr: "/define", types: "ssT", desc: "Adds a file called NAME, playing URI, looping if LOOPING is true (or changes one added this way)." },
+    Command { addr: "/query/files", types: "", desc: "Replies with every configured file." },
+    Command { addr: "/query/active", types: "", desc: "Replies with every active file." },
+    Command { addr: "/query/status", types: "", desc: "Replies with the state of the audio engine." },
+    Command { addr: "/query/namespace", types: "", desc: "Replies with this namespace, as a JSON string." },
+    Command { addr: "/channel/{channel}/patch", types: "s", desc: "Connects the channel to the JACK port PORT." },
+    Command { addr: "/send/{action}", types: "", desc: "Runs the action, sending its OSC messages." },
+    Command { addr: "/file/{file}/start", types: "f", desc: "Starts playing the file at LEVEL dB." },
+    Command { addr: "/file/{file}/load", types: "f", desc: "Loads the file, ready to be started, at LEVEL dB." },
+    Command { addr: "/file/{file}/stop", types: "", desc: "Stops playing the file." },
+    Command { addr: "/file/{file}/fade", types: "fi", desc: "Fades the file to LEVEL dB over DURATION milliseconds." },
+    Command { addr: "/file/{file}/debug", types: "", desc: "Replies with debug information about the file." },
+    Command { addr: "/file/{file}/define", types: "sT", desc: "Changes the file to URI, looping if LOOPING is true." },
+    Command { addr: "/file/{file}/undefine", types: "", desc: "Removes the file." }
+];
+
+/// Why an OSC message couldn't be turned into a `Message`.
+#[derive(Debug)]
+pub enum ParseError {
+    UnknownAddress,
+    WrongArgCount {
+        expected: usize,
+        got: usize
+    },
+    BadArgument {
+        /// 1-indexed position of the argument.
+        index: usize,
+        name: &'static str,
+        expected: &'static str,
+        got: String
+    }
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnknownAddress => write!(f, "unknown address"),
+            ParseError::WrongArgCount { expected, got } => {
+                write!(f, "expected {} argument(s), got {}", expected, got)
+            },
+            ParseError::BadArgument { index, name, expected, ref got } => {
+                write!(f, "argument {} ({}) should be {}, not {}", index, name, expected, got)
             }
         }
     }
-    fn process_msg<A: ToSocketAddrs>(&mut self, msg: OscMessage, from: A) {
-        info!("Received message: {} ({} args)", msg.addr, msg.args.as_ref().map(|x| x.len()).unwrap_or(0));
-        let addr = msg.addr.trim().split("/").collect::<Vec<_>>();
-        if let Some(m) = Self::parse_message(&addr, msg.args) {
-            self.tx.send(m).unwrap();
-            self.send_ack(from);
+}
+impl ::std::error::Error for ParseError {}
+/// Checks that there are exactly `n` arguments.
+fn check_arg_count(args: &[OscType], n: usize) -> Result<(), ParseError> {
+    if args.len() != n {
+        Err(ParseError::WrongArgCount {
+            expected: n,
+            got: args.len()
+        })
+    }
+    else {
+        Ok(())
+    }
+}
+/// Gets argument `idx` as a number, accepting any numeric type (or a numeric string).
+pub fn arg_f64(args: &[OscType], idx: usize, name: &'static str) -> Result<f64, ParseError> {
+    let ret = match args[idx] {
+        OscType::Int(i) => Some(i as f64),
+        OscType::Long(i) => Some(i as f64),
+        OscType::Float(f) => Some(f as f64),
+        OscType::Double(f) => Some(f),
+        OscType::String(ref s) => s.trim().parse::<f64>().ok(),
+        _ => None
+    };
+    match ret {
+        Some(x) if x.is_finite() => Ok(x),
+        _ => Err(ParseError::BadArgument {
+            index: idx + 1,
+            name,
+            expected: "a number",
+            got: format!("{:?}", args[idx])
+        })
+    }
+}
+/// Like `arg_f64`, but requires the number to be non-negative, and rounds it.
+pub fn arg_u64(args: &[OscType], idx: usize, name: &'static str) -> Result<u64, ParseError> {
+    let ret = arg_f64(args, idx, name)?;
+    if ret < 0.0 {
+        return Err(ParseError::BadArgument {
+            index: idx + 1,
+            name,
+            expected: "a non-negative number",
+            got: format!("{:?}", args[idx])
+        });
+    }
+    Ok(ret.round() as u64)
+}
+/// Gets argument `idx` as a string.
+pub fn arg_string(args: &[OscType], idx: usize, name: &'static str) -> Result<String, ParseError> {
+    match args[idx] {
+        OscType::String(ref s) => Ok(s.clone()),
+        ref x => Err(ParseError::BadArgument {
+            index: idx + 1,
+            name,
+            expected: "a string",
+            got: format!("{:?}", x)
+        })
+    }
+}
+/// Gets argument `idx` as a boolean, accepting booleans, numbers (non-zero is
+/// true), or the strings `true` and `false`.
+pub fn arg_bool(args: &[OscType], idx: usize, name: &'static str) -> Result<bool, ParseError> {
+    let ret = match args[idx] {
+        OscType::Bool(b) => Some(b),
+        OscType::String(ref s) => match s.trim() {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None
+        },
+        _ => arg_f64(args, idx, name).ok().map(|x| x != 0.0)
+    };
+    ret.ok_or_else(|| ParseError::BadArgument {
+        index: idx + 1,
+        name,
+        expected: "a boolean",
+        got: format!("{:?}", args[idx])
+    })
+}
+/// Gets an optional level, as the only argument.
+fn optional_level(args: &[OscType]) -> Result<Option<f64>, ParseError> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+    check_arg_count(args, 1)?;
+    Ok(Some(arg_f64(args, 0, "level")?))
+}
+/// Parses the `URI [LOOPING]` arguments to a define command, starting at `args[start]`.
+fn parse_define(args: &[OscType], start: usize) -> Result<DefinedFile, ParseError> {
+    if args.len() != start + 2 {
+        check_arg_count(args, start + 1)?;
+    }
+    Ok(DefinedFile {
+        uri: arg_string(args, start, "uri")?,
+        looping: if args.len() > start + 1 { arg_bool(args, start + 1, "looping")? } else { false }
+    })
+}
+/// Parses the arguments to `/reply_port`.
+fn parse_reply_port(args: &[OscType]) -> Result<u16, ParseError> {
+    check_arg_count(args, 1)?;
+    let port = arg_u64(args, 0, "port")?;
+    if port > 65535 {
+        return Err(ParseError::BadArgument {
+            index: 1,
+            name: "port",
+            expected: "a port number",
+            got: format!("{:?}", args[0])
+        });
+    }
+    Ok(port as u16)
+}
+fn parse_message(addr: &[&str], args: Option<Vec<OscType>>, reply: &Replier) -> Result<Message, ParseError> {
+    use self::ParseError::*;
+
+    let args = args.unwrap_or(vec![]);
+    if addr.len() == 1 {
+        return Err(UnknownAddress);
+    }
+    match addr[1] {
+        "ping" => {
+            Ok(Message::Ping)
+        },
+        "shutdown" => {
+            Ok(Message::Shutdown)
+        },
+        "debug" => {
+            Ok(Message::Debug(reply.clone()))
+        },
+        "subscribe" => {
+            Ok(Message::Subscribe(reply.clone()))
+        },
+        "reload" => {
+            Ok(Message::Reload(reply.clone()))
+        },
+        "unsubscribe" => {
+            Ok(Message::Unsubscribe(reply.clone()))
+        },
+        "send" => {
+            if addr.len() != 3 {
+                return Err(UnknownAddress);
+            }
+            Ok(Message::SendAction(addr[2].into(), args))
+        },
+        "channel" => {
+            if addr.len() != 4 || addr[3] != "patch" {
+                return Err(UnknownAddress);
+            }
+            let idx = addr[2].parse::<usize>().map_err(|_| UnknownAddress)?;
+            if args.is_empty() {
+                check_arg_count(&args, 1)?;
+            }
+            let ports = (0..args.len())
+                .map(|i| arg_string(&args, i, "port"))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Message::PatchChannel(idx, ports))
+        },
+        "define" => {
+            if addr.len() != 2 {
+                return Err(UnknownAddress);
+            }
+            if args.is_empty() {
+                check_arg_count(&args, 2)?;
+            }
+            let name = arg_string(&args, 0, "name")?;
+            Ok(Message::DefineFile(name, parse_define(&args, 1)?, reply.clone()))
+        },
+        "query" => {
+            if addr.len() != 3 {
+                return Err(UnknownAddress);
+            }
+            let q = match addr[2] {
+                "files" => Query::Files,
+                "active" => Query::Active,
+                "status" => Query::Status,
+                "namespace" => Query::Namespace,
+                _ => return Err(UnknownAddress)
+            };
+            Ok(Message::Query(q, reply.clone()))
+        },
+        "file" => {
+            if addr.len() <= 3 {
+                return Err(UnknownAddress);
+            }
+            match addr[3] {
+                "start" => {
+                    let level = optional_level(&args)?;
+                    Ok(Message::PlayFile(addr[2].into(), level))
+                },
+                "load" => {
+                    let level = optional_level(&args)?;
+                    Ok(Message::LoadFile(addr[2].into(), level))
+                },
+                "define" => {
+                    Ok(Message::DefineFile(addr[2].into(), parse_define(&args, 0)?, reply.clone()))
+                },
+                "undefine" => {
+                    Ok(Message::UndefineFile(addr[2].into()))
+                },
+                "debug" => {
+                    Ok(Message::DebugFile(addr[2].into(), reply.clone()))
+                },
+                "stop" => {
+                    Ok(Message::StopFile(addr[2].into()))
+                },
+                "fade" => {
+                    check_arg_count(&args, 2)?;
+                    let target = arg_f64(&args, 0, "level")?;
+                    let dur_ms = arg_u64(&args, 1, "duration")?;
+                    Ok(Message::FadeFile(addr[2].into(), target, dur_ms))
+                },
+                _ => {
+                    Err(UnknownAddress)
+                }
+            }
+        },
+        _ => {
+            Err(UnknownAddress)
+        }
+    }
+}
+/// Dispatches a single OSC message.
+///
+/// `jack_up` says whether the audio engine is running; if it isn't, commands
+/// that need it get `/error` instead of `/ack`.
+pub fn process_msg(tx: &Sender<Message>, acl: &AccessControl, jack_up: &AtomicBool, mut msg: OscMessage, reply: &Replier) {
+    if !acl.check_token(&mut msg) {
+        acl.reject(&reply.describe(), "missing or incorrect token");
+        return;
+    }
+    info!("Received message: {} ({} args)", msg.addr, msg.args.as_ref().map(|x| x.len()).unwrap_or(0));
+    let addr = msg.addr.trim().split("/").collect::<Vec<_>>();
+    if addr == ["", "reply_port"] {
+        // This one changes how we reply, so it's dealt with here, not in the context.
+        let args = msg.args.unwrap_or(vec![]);
+        match parse_reply_port(&args) {
+            Ok(port) => {
+                match reply.set_reply_port(port) {
+                    Ok(_) => send_ack(reply),
+                    Err(e) => reply.send(OscMessage {
+                        addr: "/error".into(),
+                        args: Some(vec![OscType::String(e.to_string())])
+                    })
+                }
+            },
+            Err(e) => send_bad_args(addr.join("/"), e, reply)
+        }
+        return;
+    }
+    match parse_message(&addr, msg.args, reply) {
+        Ok(ref m) if m.needs_engine() && !jack_up.load(Ordering::SeqCst) => {
+            warn!("Rejecting {}: JACK isn't running", addr.join("/"));
+            reply.send(OscMessage {
+                addr: "/error".into(),
+                args: Some(vec![OscType::String("JACK isn't running; waiting for it to come back.".into())])
+            });
+        },
+        Ok(m) => {
+            tx.send(m).unwrap();
+            send_ack(reply);
             info!("ACK sent");
+        },
+        Err(ParseError::UnknownAddress) => {
+            send_unknown(addr.join("/"), reply);
+        },
+        Err(e) => {
+            send_bad_args(addr.join("/"), e, reply);
+        }
+    }
+}
+fn send_ack(reply: &Replier) {
+    reply.send(OscMessage {
+        addr: "/ack".into(),
+        args: None
+    });
+}
+fn send_unknown(addr: String, reply: &Replier) {
+    warn!("Unknown OSC address: {}", addr);
+    reply.send(OscMessage {
+        addr: "/unknown_address".into(),
+        args: Some(vec![OscType::String(addr)])
+    });
+}
+fn send_bad_args(addr: String, err: ParseError, reply: &Replier) {
+    warn!("Bad arguments for {}: {}", addr, err);
+    reply.send(OscMessage {
+        addr: "/bad_arguments".into(),
+        args: Some(vec![OscType::String(addr), OscType::String(err.to_string())])
+    });
+}
+/// Decodes an OSC packet, and dispatches whatever's in it.
+///
+/// This is shared between all of the ways to receive OSC.
+pub fn process_packet(tx: &Sender<Message>, acl: &AccessControl, jack_up: &AtomicBool, buf: &[u8], reply: &Replier) {
+    match rosc::decoder::decode(buf) {
+        Ok(pkt) => {
+            if let OscPacket::Message(m) = pkt {
+                process_msg(tx, acl, jack_up, m, reply);
+            }
+            else {
+                warn!("Received a bundle! (unimplemented)");
+                reply.send(OscMessage {
+                    addr: "/no_bundles_please".into(),
+                    args: None
+                });
+            }
+        },
+        Err(e) => {
+            warn!("Failed to decode: {:?}", e);
         }
-        else {
-            self.send_unknown(addr.join("/"), from);
-        }
-    }
-    fn send_ack<A: ToSocketAddrs>(&mut self, a: A) {
-        self.send(OscMessage {
-            addr: "/ack".into(),
-            args: None
-        }, a);
-    }
-    fn send_unknown<A: ToSocketAddrs>(&mut self, addr: String, a: A) {
-        warn!("Unknown OSC address: {}", addr);
-        self.send(OscMessage {
-            addr: "/unknown_address".into(),
-            args: Some(vec![OscType::String(addr)])
-        }, a);
-    }
-    fn _send<A: ToSocketAddrs>(&mut self, msg: OscMessage, a: A) -> Result<(), Error> {
-        let msg_buf = rosc::encoder::encode(&OscPacket::Message(msg))
-            .map_err(|e| format_err!("{:?}", e))?;
-        self.sock.send_to(&msg_buf, a)?;
-        Ok(())
     }
-    fn send<A: ToSocketAddrs>(&mut self, msg: OscMessage, a: A) {
-        if let Err(e) = self._send(msg, a) {
-            warn!("Failed to send OSC message: {}", e);
+}
+
+/// Binds a UDP socket as described by `spec`, joining any multicast groups.
+pub fn bind_udp(spec: &Listen) -> Result<UdpSocket, Error> {
+    let sock = UdpSocket::bind(spec.addr())
+        .map_err(|e| format_err!("failed binding {}: {}", spec.addr(), e))?;
+    if let Listen::Multicast { ref groups, ref interface, .. } = *spec {
+        for group in groups.iter() {
+            let group: IpAddr = group.parse()
+                .map_err(|e| format_err!("invalid multicast group '{}': {}", group, e))?;
+            info!("[+] Joining multicast group {} on {}", group, spec.addr());
+            match group {
+                IpAddr::V4(g) => {
+                    let iface = match *interface {
+                        Some(ref i) => i.parse()
+                            .map_err(|e| format_err!("invalid interface address '{}': {}", i, e))?,
+                        None => ::std::net::Ipv4Addr::new(0, 0, 0, 0)
+                    };
+                    sock.join_multicast_v4(&g, &iface)?;
+                },
+                IpAddr::V6(g) => {
+                    let iface = match *interface {
+                        Some(ref i) => i.parse::<u32>()
+                            .map_err(|e| format_err!("invalid interface index '{}': {}", i, e))?,
+                        None => 0
+                    };
+                    sock.join_multicast_v6(&g, iface)?;
+                }
+            }
         }
     }
-    pub fn run(&mut self) -> ! {
+    Ok(sock)
+}
+
+pub struct OscContext {
+    pub sock: Arc<UdpSocket>,
+    pub tx: Sender<Message>,
+    pub acl: Arc<AccessControl>,
+    /// Whether the audio engine is running (kept up to date by the `Context`).
+    pub jack_up: Arc<AtomicBool>,
+    pub routes: Arc<Mutex<ReplyRoutes>>,
+    /// Set when we're shutting down, to make `run` return.
+    pub stop: Arc<AtomicBool>
+}
+
+impl OscContext {
+    /// How often `run` checks whether it should stop.
+    const STOP_CHECK_MS: u64 = 250;
+
+    pub fn run(&mut self) {
         let mut buf = [0u8; rosc::decoder::MTU];
 
-        loop {
+        if let Err(e) = self.sock.set_read_timeout(Some(Duration::from_millis(Self::STOP_CHECK_MS))) {
+            warn!("Failed setting socket timeout (shutdown may hang): {}", e);
+        }
+        while !self.stop.load(Ordering::SeqCst) {
             match self.sock.recv_from(&mut buf) {
                 Ok((size, addr)) => {
-                    info!("Received packet from {} (size: {})", addr, size);
-                    match rosc::decoder::decode(&buf[..size]) {
-                        Ok(pkt) => {
-                            if let OscPacket::Message(m) = pkt {
-                                self.process_msg(m, addr);
-                            }
-                            else {
-                                warn!("Received a bundle! (unimplemented)");
-                                self.send(OscMessage {
-                                    addr: "/no_bundles_please".into(),
-                                    args: None
-                                }, addr);
-                            }
-                        },
-                        Err(e) => {
-                            warn!("Failed to decode: {:?}", e);
-                        }
+                    if !self.acl.source_allowed(addr.ip()) {
+                        self.acl.reject(&format!("udp:{}", addr), "source address not allowed");
+                        continue;
                     }
+                    info!("Received packet from {} (size: {})", addr, size);
+                    let reply = Replier::Udp {
+                        sock: self.sock.clone(),
+                        routes: self.routes.clone(),
+                        addr
+                    };
+                    process_packet(&self.tx, &self.acl, &self.jack_up, &buf[..size], &reply);
                 },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {},
                 Err(e) => {
                     error!("Error receiving from socket: {}", e);
-                    self.tx.send(Message::Shutdown).unwrap();
-                    ::std::thread::sleep(::std::time::Duration::from_millis(1000));
+                    let _ = self.tx.send(Message::Shutdown);
+                    break;
                 },
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes up an argument of OSC type `tag`.
+    fn example_arg(tag: char) -> OscType {
+        match tag {
+            'f' => OscType::Float(0.0),
+            'i' => OscType::Int(1234),
+            's' => OscType::String("example".into()),
+            'T' => OscType::Bool(true),
+            x => panic!("no example for type tag {}", x)
+        }
+    }
+    #[test]
+    fn every_command_parses() {
+        let reply = Replier::Discard { name: "test".into() };
+        for cmd in COMMANDS {
+            let addr = cmd.addr
+                .replace("{file}", "cake")
+                .replace("{channel}", "0")
+                .replace("{action}", "go");
+            let args = cmd.types.chars().map(example_arg).collect::<Vec<_>>();
+            if addr == "/reply_port" {
+                // Handled by `process_msg` itself, not `parse_message`.
+                assert!(parse_reply_port(&args).is_ok(), "{} didn't parse", addr);
+                continue;
+            }
+            let split = addr.split('/').collect::<Vec<_>>();
+            let args = if !args.is_empty() { Some(args) } else { None };
+            match parse_message(&split, args, &reply) {
+                Ok(_) => {},
+                Err(e) => panic!("{} ({}) didn't parse: {}", addr, cmd.types, e)
+            }
+        }
+    }
+    #[test]
+    fn connection_count() {
+        let count = ConnectionCount::default();
+        let mut guards = (0..MAX_CONNECTIONS).map(|_| count.acquire().unwrap()).collect::<Vec<_>>();
+        assert!(count.acquire().is_none());
+        guards.pop();
+        assert!(count.acquire().is_some());
+        guards.clear();
+        assert_eq!(count.0.load(Ordering::SeqCst), 0);
+    }
+}
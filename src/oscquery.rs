@@ -0,0 +1,318 @@
+//! An OSCQuery server, so that controllers can discover what commands we accept.
+//!
+//! See https://github.com/Vidvox/OSCQueryProposal for the details.
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+use tiny_http::{Server, Request, Response, Header};
+use serde_json::{self, Map, Value};
+use rosc::OscType;
+use ctxt::{Context, Message, Query};
+use osc::{Replier, COMMANDS};
+use http;
+
+/// ACCESS values, as defined by the OSCQuery spec.
+const ACCESS_NONE: u8 = 0;
+const ACCESS_READ: u8 = 1;
+const ACCESS_WRITE: u8 = 2;
+
+/// Makes a container node, with the children `contents`.
+fn container(path: &str, desc: &str, contents: Map<String, Value>) -> Value {
+    json!({
+        "FULL_PATH": path,
+        "DESCRIPTION": desc,
+        "ACCESS": ACCESS_NONE,
+        "CONTENTS": contents
+    })
+}
+/// Makes a node for a command taking arguments with OSC type tags `types`.
+///
+/// Commands without arguments are given the type `N` (an impulse).
+fn method(path: &str, desc: &str, types: &str, access: u8) -> Value {
+    let types = if types.is_empty() { "N" } else { types };
+    json!({
+        "FULL_PATH": path,
+        "DESCRIPTION": desc,
+        "ACCESS": access,
+        "TYPE": types
+    })
+}
+/// Describes the container at `path`, for `insert`.
+fn container_desc(path: &[&str]) -> String {
+    match path {
+        [] => "mfl-gramophone".into(),
+        ["query"] => "Information about the server's state.".into(),
+        ["channel"] => "Output channels.".into(),
+        ["channel", n] => format!("Output channel {}.", n),
+        ["send"] => "Configured actions.".into(),
+        ["file"] => "Configured files.".into(),
+        ["file", name] => format!("The file '{}'.", name),
+        _ => "".into()
+    }
+}
+/// Puts `node` at `path` under `root`, making any containers in between.
+///
+/// Fails if something else is already in the way (e.g. a method where a
+/// container should be).
+fn insert(root: &mut Value, path: &str, node: Value) -> Result<(), String> {
+    let segs = path.split('/').filter(|x| !x.is_empty()).collect::<Vec<_>>();
+    let mut cur = root;
+    for i in 0..segs.len() {
+        let contents = match cur.get_mut("CONTENTS").and_then(|c| c.as_object_mut()) {
+            Some(c) => c,
+            None => return Err(format!("/{} isn't a container", segs[..i].join("/")))
+        };
+        if i == segs.len() - 1 {
+            if contents.get(segs[i]).and_then(|n| n.get("CONTENTS")).is_some() && node.get("CONTENTS").is_none() {
+                return Err(format!("{} is already a container", path));
+            }
+            contents.insert(segs[i].into(), node);
+            return Ok(());
+        }
+        let full = format!("/{}", segs[..i + 1].join("/"));
+        cur = contents.entry(segs[i].to_string())
+            .or_insert_with(|| container(&full, &container_desc(&segs[..i + 1]), Map::new()));
+    }
+    Ok(())
+}
+/// Builds the OSCQuery namespace, describing every command in `osc::COMMANDS`
+/// for the current configuration.
+pub fn build_namespace(ctx: &Context) -> Value {
+    let mut root = container("/", &container_desc(&[]), Map::new());
+    // These should be there even when there's nothing in them.
+    for path in ["/query", "/channel", "/send", "/file"].iter() {
+        let segs = path.split('/').filter(|x| !x.is_empty()).collect::<Vec<_>>();
+        insert(&mut root, path, container(path, &container_desc(&segs), Map::new()))
+            .expect("the namespace skeleton is broken");
+    }
+    let channels = (0..ctx.cfg.channels.len()).map(|i| i.to_string()).collect::<Vec<_>>();
+    // Names with a `/` in them can't be addressed over OSC (and would end up
+    // somewhere odd in the namespace), so they're left out.
+    let actions = ctx.actions.names().into_iter()
+        .filter(|n| !n.contains('/'))
+        .collect::<Vec<_>>();
+    let mut files = ctx.cfg.files.keys()
+        .filter(|n| !n.contains('/'))
+        .cloned()
+        .collect::<Vec<_>>();
+    files.sort();
+    for cmd in COMMANDS {
+        let (placeholder, names) = if cmd.addr.contains("{file}") {
+            ("{file}", files.clone())
+        }
+        else if cmd.addr.contains("{channel}") {
+            ("{channel}", channels.clone())
+        }
+        else if cmd.addr.contains("{action}") {
+            ("{action}", actions.clone())
+        }
+        else {
+            ("", vec![String::new()])
+        };
+        for name in names {
+            let path = if !placeholder.is_empty() {
+                cmd.addr.replace(placeholder, &name)
+            }
+            else {
+                cmd.addr.to_string()
+            };
+            let mut node = method(&path, cmd.desc, cmd.types, ACCESS_WRITE);
+            match cmd.addr {
+                "/file/{file}/start" | "/file/{file}/load" => {
+                    node["RANGE"] = json!([{ "MIN": -60.0, "MAX": 12.0 }]);
+                },
+                "/file/{file}/fade" => {
+                    node["RANGE"] = json!([{ "MIN": -60.0, "MAX": 12.0 }, { "MIN": 0 }]);
+                },
+                "/file/{file}/define" | "/file/{file}/undefine" => {
+                    // Only files that were defined in the first place can be
+                    // changed this way.
+                    if !ctx.defined.contains_key(&name) {
+                        continue;
+                    }
+                },
+                "/channel/{channel}/patch" => {
+                    let idx = name.parse::<usize>().unwrap();
+                    // One string per port it's connected to right now, or a
+                    // description of what it should be connected to if there
+                    // aren't any.
+                    let mut value = ctx.engine.as_ref()
+                        .map(|e| e.patched(idx).to_vec())
+                        .unwrap_or(vec![]);
+                    if value.is_empty() {
+                        value.push(ctx.cfg.channels[idx].describe());
+                    }
+                    node["ACCESS"] = json!(ACCESS_READ | ACCESS_WRITE);
+                    node["TYPE"] = json!("s".repeat(value.len()));
+                    node["VALUE"] = json!(value);
+                },
+                _ => {}
+            }
+            if let Err(e) = insert(&mut root, &path, node) {
+                warn!("Leaving {} out of the OSCQuery namespace: {}", path, e);
+            }
+        }
+    }
+    for name in files {
+        let base = format!("/file/{}", name);
+        let mut level = method(&format!("{}/level", base), "The file's current volume, in decibels.", "f", ACCESS_READ);
+        let mut state = method(&format!("{}/state", base), "The file's current state.", "s", ACCESS_READ);
+        match ctx.active_files.get(&name) {
+            Some(af) => {
+                level["VALUE"] = json!([af.level()]);
+                state["VALUE"] = json!([af.state()]);
+            },
+            None => {
+                state["VALUE"] = json!(["stopped"]);
+            }
+        }
+        for &(leaf, ref node) in [("level", level), ("state", state)].iter() {
+            let path = format!("{}/{}", base, leaf);
+            if let Err(e) = insert(&mut root, &path, node.clone()) {
+                warn!("Leaving {} out of the OSCQuery namespace: {}", path, e);
+            }
+        }
+    }
+    root
+}
+/// Finds the node at `path` in the namespace `root`.
+fn find_node<'a>(root: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut node = root;
+    for seg in path.split('/').filter(|x| !x.is_empty()) {
+        node = node.get("CONTENTS")?.get(&http::percent_decode(seg) as &str)?;
+    }
+    Some(node)
+}
+
+pub struct OscQueryContext {
+    pub server: Server,
+    pub tx: Sender<Message>,
+    /// The address OSC commands are accepted on (over UDP).
+    pub osc_addr: SocketAddr
+}
+impl OscQueryContext {
+    fn respond_json(req: Request, code: u16, val: &Value) {
+        let resp = Response::from_string(val.to_string())
+            .with_status_code(code)
+            .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+        if let Err(e) = req.respond(resp) {
+            warn!("Failed to send OSCQuery response: {}", e);
+        }
+    }
+    fn respond_status(req: Request, code: u16) {
+        if let Err(e) = req.respond(Response::empty(code)) {
+            warn!("Failed to send OSCQuery response: {}", e);
+        }
+    }
+    /// Works out what address clients should send OSC to.
+    ///
+    /// If we're listening on every address (e.g. `0.0.0.0`), that isn't much use
+    /// to a client, so the host it used to reach the OSCQuery server is given
+    /// instead.
+    fn osc_host(&self, req: &Request) -> String {
+        let ip = self.osc_addr.ip();
+        if !ip.is_unspecified() {
+            return ip.to_string();
+        }
+        let host = req.headers().iter()
+            .find(|h| h.field.equiv("Host"))
+            .map(|h| h.value.as_str().trim());
+        match host {
+            // An IPv6 address, like `[::1]:8080`.
+            Some(h) if h.starts_with('[') => {
+                h[1..].split(']').next().unwrap_or("").to_string()
+            },
+            Some(h) if !h.is_empty() => {
+                h.split(':').next().unwrap_or("").to_string()
+            },
+            _ => ip.to_string()
+        }
+    }
+    fn host_info(&self, req: &Request) -> Value {
+        json!({
+            "NAME": "mfl-gramophone",
+            "OSC_IP": self.osc_host(req),
+            "OSC_PORT": self.osc_addr.port(),
+            "OSC_TRANSPORT": "UDP",
+            "EXTENSIONS": {
+                "ACCESS": true,
+                "VALUE": true,
+                "RANGE": true,
+                "DESCRIPTION": true,
+                "TYPE": true,
+                "FULL_PATH": true,
+                "CONTENTS": true
+            }
+        })
+    }
+    fn get_namespace(&self, req: &Request) -> Result<Value, (u16, String)> {
+        let (rtx, rrx) = mpsc::channel();
+        let reply = Replier::Channel {
+            tx: rtx,
+            name: format!("oscquery:{}", req.remote_addr())
+        };
+        let replies = http::run_command(&self.tx, Message::Query(Query::Namespace, reply.clone()), reply, rrx)?;
+        for msg in replies {
+            if let Some(OscType::String(ref s)) = msg.args.as_ref().and_then(|a| a.first()) {
+                return serde_json::from_str(s)
+                    .map_err(|e| (500, format!("invalid namespace: {}", e)));
+            }
+        }
+        Err((500, "no namespace returned".into()))
+    }
+    fn handle_request(&self, req: Request) {
+        info!("OSCQuery {} {} from {}", req.method(), req.url(), req.remote_addr());
+        let (path, attr) = {
+            let mut split = req.url().splitn(2, '?');
+            let path = split.next().unwrap_or("/").to_string();
+            let attr = split.next().map(|x| x.to_string());
+            (path, attr)
+        };
+        if attr.as_deref() == Some("HOST_INFO") {
+            let hi = self.host_info(&req);
+            Self::respond_json(req, 200, &hi);
+            return;
+        }
+        let ns = match self.get_namespace(&req) {
+            Ok(ns) => ns,
+            Err((code, e)) => {
+                warn!("Failed to get OSCQuery namespace: {}", e);
+                Self::respond_status(req, code);
+                return;
+            }
+        };
+        let node = match find_node(&ns, &path) {
+            Some(n) => n,
+            None => {
+                Self::respond_status(req, 404);
+                return;
+            }
+        };
+        match attr {
+            Some(attr) => {
+                match node.get(&attr as &str) {
+                    Some(val) => {
+                        let mut ret = Map::new();
+                        ret.insert(attr.clone(), val.clone());
+                        Self::respond_json(req, 200, &Value::Object(ret));
+                    },
+                    // The spec says "no value" should be a 204.
+                    None => Self::respond_status(req, 204)
+                }
+            },
+            None => Self::respond_json(req, 200, node)
+        }
+    }
+    pub fn run(&mut self) -> ! {
+        loop {
+            match self.server.recv() {
+                Ok(req) => self.handle_request(req),
+                Err(e) => {
+                    error!("Error receiving OSCQuery request: {}", e);
+                    ::std::thread::sleep(Duration::from_millis(1000));
+                }
+            }
+        }
+    }
+}
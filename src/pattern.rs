@@ -0,0 +1,252 @@
+//! OSC 1.0 address pattern matching.
+//!
+//! Supports `?` (any single character), `*` (any sequence of characters),
+//! `[abc]` / `[a-z]` / `[!a-z]` (character classes), and `{foo,bar}` (alternatives).
+//!
+//! Patterns come from the network, and get matched against every file name on
+//! the `Context` thread, so matching has to take a reasonable amount of time no
+//! matter how many stars there are: it uses the usual iterative glob matcher,
+//! which only ever goes back to the last `*` it saw.
+
+use std::collections::HashSet;
+
+/// Returns whether `s` contains any pattern-matching characters.
+pub fn is_pattern(s: &str) -> bool {
+    s.contains(&['*', '?', '[', '{'][..])
+}
+/// Returns whether the OSC address pattern `pat` matches `name`.
+///
+/// If you're matching the same pattern against lots of names, use `Pattern`.
+pub fn matches(pat: &str, name: &str) -> bool {
+    Pattern::new(pat).matches(name)
+}
+
+enum Token {
+    Char(char),
+    /// `?`
+    Any,
+    /// `*` (or several in a row).
+    Star,
+    /// `[...]`, as a list of inclusive ranges.
+    Class {
+        negate: bool,
+        ranges: Vec<(char, char)>
+    },
+    /// `{...}`
+    Alternatives(Vec<String>)
+}
+impl Token {
+    /// Whether this token (which isn't `Star` or `Alternatives`) matches `ch`.
+    fn matches_char(&self, ch: char) -> bool {
+        match *self {
+            Token::Char(c) => c == ch,
+            Token::Any => true,
+            Token::Class { negate, ref ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= ch && ch <= hi) != negate
+            },
+            Token::Star | Token::Alternatives(_) => false
+        }
+    }
+}
+/// An OSC address pattern, parsed so that it can be matched against lots of
+/// names without redoing any work.
+pub struct Pattern {
+    /// `None` if the pattern is malformed (e.g. has an unterminated `[`), in
+    /// which case it doesn't match anything.
+    tokens: Option<Vec<Token>>
+}
+impl Pattern {
+    pub fn new(pat: &str) -> Self {
+        Pattern { tokens: Self::parse(pat) }
+    }
+    fn parse(pat: &str) -> Option<Vec<Token>> {
+        let mut ret = vec![];
+        let mut chars = pat.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' => {
+                    if let Some(&Token::Star) = ret.last() {
+                        continue;
+                    }
+                    ret.push(Token::Star);
+                },
+                '?' => ret.push(Token::Any),
+                '[' => {
+                    let mut set = vec![];
+                    loop {
+                        match chars.next()? {
+                            ']' => break,
+                            c => set.push(c)
+                        }
+                    }
+                    let negate = set.first() == Some(&'!');
+                    let set = if negate { &set[1..] } else { &set[..] };
+                    let mut ranges = vec![];
+                    let mut i = 0;
+                    while i < set.len() {
+                        if i + 2 < set.len() && set[i + 1] == '-' {
+                            ranges.push((set[i], set[i + 2]));
+                            i += 3;
+                        }
+                        else {
+                            ranges.push((set[i], set[i]));
+                            i += 1;
+                        }
+                    }
+                    ret.push(Token::Class { negate, ranges });
+                },
+                '{' => {
+                    let mut alts = vec![String::new()];
+                    loop {
+                        match chars.next()? {
+                            '}' => break,
+                            ',' => alts.push(String::new()),
+                            c => alts.last_mut().unwrap().push(c)
+                        }
+                    }
+                    ret.push(Token::Alternatives(alts));
+                },
+                c => ret.push(Token::Char(c))
+            }
+        }
+        Some(ret)
+    }
+    /// Returns whether this pattern matches `name`.
+    pub fn matches(&self, name: &str) -> bool {
+        match self.tokens {
+            Some(ref tokens) => Self::match_from(tokens, 0, name, 0, &mut HashSet::new()),
+            None => false
+        }
+    }
+    /// Matches `tokens[ti..]` against `name[ni..]`.
+    ///
+    /// Alternatives (which can be different lengths) are tried by calling this
+    /// again for the rest of the pattern; `failed` remembers which of those
+    /// didn't match, so that none of them gets tried twice.
+    fn match_from(tokens: &[Token], mut ti: usize, name: &str, mut ni: usize, failed: &mut HashSet<(usize, usize)>) -> bool {
+        // Where to go back to if something doesn't match: the token after the
+        // last `*`, and where in `name` that `*` has got to.
+        let mut star: Option<(usize, usize)> = None;
+        loop {
+            let step = match tokens.get(ti) {
+                None if ni == name.len() => return true,
+                None => None,
+                Some(&Token::Star) => {
+                    star = Some((ti + 1, ni));
+                    ti += 1;
+                    continue;
+                },
+                Some(Token::Alternatives(alts)) => {
+                    for alt in alts.iter() {
+                        if !name[ni..].starts_with(alt as &str) {
+                            continue;
+                        }
+                        let next = (ti + 1, ni + alt.len());
+                        if failed.contains(&next) {
+                            continue;
+                        }
+                        if Self::match_from(tokens, next.0, name, next.1, failed) {
+                            return true;
+                        }
+                        failed.insert(next);
+                    }
+                    None
+                },
+                Some(tok) => {
+                    name[ni..].chars().next()
+                        .filter(|&c| tok.matches_char(c))
+                        .map(|c| c.len_utf8())
+                },
+            };
+            match step {
+                Some(len) => {
+                    ti += 1;
+                    ni += len;
+                },
+                None => {
+                    // Let the last `*` swallow one more character, and try again.
+                    let (sti, sni) = match star {
+                        Some(s) => s,
+                        None => return false
+                    };
+                    let len = match name[sni..].chars().next() {
+                        Some(c) => c.len_utf8(),
+                        None => return false
+                    };
+                    star = Some((sti, sni + len));
+                    ti = sti;
+                    ni = sni + len;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(matches("cake", "cake"));
+        assert!(!matches("cake", "cakes"));
+        assert!(!matches("cake", "cak"));
+        assert!(matches("", ""));
+    }
+    #[test]
+    fn question_mark() {
+        assert!(matches("c?ke", "cake"));
+        assert!(matches("c?ke", "cöke"));
+        assert!(!matches("c?ke", "cke"));
+        assert!(!matches("cake?", "cake"));
+    }
+    #[test]
+    fn star() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("sfx.*", "sfx.door"));
+        assert!(matches("*door*", "sfx.door.slam"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(matches("a**c", "abc"));
+        assert!(!matches("a*c", "abcd"));
+        assert!(!matches("sfx.*", "music.door"));
+    }
+    #[test]
+    fn classes() {
+        assert!(matches("cue[0-9]", "cue5"));
+        assert!(!matches("cue[0-9]", "cuex"));
+        assert!(matches("[abc]at", "bat"));
+        assert!(!matches("[abc]at", "rat"));
+        assert!(matches("cue[!0-9]", "cuex"));
+        assert!(!matches("cue[!0-9]", "cue5"));
+        // A trailing `-` is just a character.
+        assert!(matches("[a-]", "-"));
+        assert!(!matches("[]", "a"));
+    }
+    #[test]
+    fn alternatives() {
+        assert!(matches("{cake,pie}", "cake"));
+        assert!(matches("{cake,pie}", "pie"));
+        assert!(!matches("{cake,pie}", "tart"));
+        assert!(matches("{a,ab}c", "abc"));
+        assert!(matches("*.{wav,flac}", "door.flac"));
+        assert!(!matches("*.{wav,flac}", "door.mp3"));
+        assert!(matches("x{,y}", "x"));
+    }
+    #[test]
+    fn unterminated() {
+        assert!(!matches("cue[0-9", "cue5"));
+        assert!(!matches("cue[0-9", "cue[0-9"));
+        assert!(!matches("{cake,pie", "cake"));
+        assert!(!matches("{cake,pie", "{cake,pie"));
+    }
+    #[test]
+    fn pathological() {
+        let name = "a".repeat(200);
+        assert!(!matches("*?*?*?*?*?*?*?*?*?*?*?*?*?*?*?*?x", &name));
+        assert!(matches("*?*?*?*?*?*?*?*?*?*?*?*?*?*?*?*?a", &name));
+        let alts = "{a,aa}".repeat(30);
+        assert!(!matches(&format!("{}b", alts), &name));
+        assert!(!matches(&format!("*{}*b", alts), &name));
+    }
+}
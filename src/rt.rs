@@ -0,0 +1,121 @@
+//! Passing messages to and from JACK process callbacks.
+//!
+//! The process callback runs on JACK's realtime thread, where allocating memory
+//! or taking locks can cause xruns, so `std::sync::mpsc` is out. Instead, messages
+//! are encoded as a fixed number of bytes and passed through a (preallocated,
+//! lock-free) JACK ring buffer. Since the callback can't wake anything up, the
+//! receiving end polls.
+
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
+use jack::{RingBuffer, RingBufferReader, RingBufferWriter};
+use failure::Error;
+
+/// The biggest `RtMessage::SIZE` allowed.
+pub const MAX_SIZE: usize = 64;
+/// How often, in milliseconds, `RtReceiver::recv_timeout` checks for messages.
+const POLL_MS: u64 = 1;
+
+/// Something that can be sent through an `RtSender`, as exactly `SIZE` bytes.
+pub trait RtMessage: Sized {
+    const SIZE: usize;
+    fn encode(&self, buf: &mut [u8]);
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+impl RtMessage for u64 {
+    const SIZE: usize = 8;
+    fn encode(&self, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (*self >> (i * 8)) as u8;
+        }
+    }
+    fn decode(buf: &[u8]) -> Option<Self> {
+        Some(buf.iter().enumerate().fold(0, |acc, (i, &b)| acc | ((b as u64) << (i * 8))))
+    }
+}
+/// The sending half of a channel made with `channel`.
+pub struct RtSender<T> {
+    writer: RingBufferWriter,
+    closed: Arc<AtomicBool>,
+    _msg: PhantomData<T>
+}
+impl<T: RtMessage> RtSender<T> {
+    /// Sends `msg`, without blocking or allocating. If the channel is full, the
+    /// message is dropped, and this returns `false`.
+    pub fn send(&mut self, msg: &T) -> bool {
+        if self.writer.space() < T::SIZE {
+            return false;
+        }
+        let mut buf = [0u8; MAX_SIZE];
+        msg.encode(&mut buf[..T::SIZE]);
+        self.writer.write_buffer(&buf[..T::SIZE]);
+        true
+    }
+}
+impl<T> Drop for RtSender<T> {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+/// The receiving half of a channel made with `channel`.
+pub struct RtReceiver<T> {
+    reader: RingBufferReader,
+    closed: Arc<AtomicBool>,
+    _msg: PhantomData<T>
+}
+impl<T: RtMessage> RtReceiver<T> {
+    /// Takes the next message, if there is one, without blocking or allocating.
+    pub fn try_recv(&mut self) -> Option<T> {
+        if self.reader.space() < T::SIZE {
+            return None;
+        }
+        let mut buf = [0u8; MAX_SIZE];
+        self.reader.read_buffer(&mut buf[..T::SIZE]);
+        T::decode(&buf[..T::SIZE])
+    }
+    /// Waits up to `timeout` for the next message.
+    ///
+    /// Fails with `Disconnected` once the `RtSender` has been dropped, and
+    /// every message it sent has been received.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Check this first, so that nothing sent just before the sender
+            // was dropped gets missed.
+            let closed = self.closed.load(Ordering::SeqCst);
+            if let Some(msg) = self.try_recv() {
+                return Ok(msg);
+            }
+            if closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            if Instant::now() >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            ::std::thread::sleep(Duration::from_millis(POLL_MS));
+        }
+    }
+}
+/// Makes a channel with room for `capacity` messages.
+pub fn channel<T: RtMessage>(capacity: usize) -> Result<(RtSender<T>, RtReceiver<T>), Error> {
+    assert!(T::SIZE <= MAX_SIZE);
+    // JACK ring buffers can hold one byte less than their size.
+    let mut rb = RingBuffer::new(capacity * T::SIZE + 1)
+        .map_err(|e| format_err!("failed allocating ring buffer: {:?}", e))?;
+    rb.mlock();
+    let (reader, writer) = rb.into_reader_writer();
+    let closed = Arc::new(AtomicBool::new(false));
+    let tx = RtSender {
+        writer,
+        closed: closed.clone(),
+        _msg: PhantomData
+    };
+    let rx = RtReceiver {
+        reader, closed,
+        _msg: PhantomData
+    };
+    Ok((tx, rx))
+}
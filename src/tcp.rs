@@ -0,0 +1,255 @@
+//! Receiving OSC over TCP, using either SLIP (OSC 1.1) or length-prefix (OSC 1.0) framing.
+
+use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write, BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use ctxt::Message;
+use config::Framing;
+use osc::{self, Replier, StreamFormat, ConnectionCount};
+use auth::AccessControl;
+use failure::Error;
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+/// The biggest frame we'll accept, so that a misbehaving client can't make us
+/// use up all the memory.
+const MAX_FRAME: usize = ::rosc::decoder::MTU * 64;
+
+/// Wraps up the packet `buf` into a frame, ready to send over a stream.
+pub fn encode_frame(framing: Framing, buf: &[u8]) -> Vec<u8> {
+    match framing {
+        Framing::Slip => {
+            // Double-ended SLIP, as recommended by OSC 1.1.
+            let mut ret = Vec::with_capacity(buf.len() + 2);
+            ret.push(SLIP_END);
+            for &b in buf.iter() {
+                match b {
+                    SLIP_END => ret.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+                    SLIP_ESC => ret.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+                    b => ret.push(b)
+                }
+            }
+            ret.push(SLIP_END);
+            ret
+        },
+        Framing::Length => {
+            let len = buf.len() as u32;
+            let mut ret = Vec::with_capacity(buf.len() + 4);
+            ret.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+            ret.extend_from_slice(buf);
+            ret
+        }
+    }
+}
+/// Reads the next frame from `stream`, returning `None` if the stream has ended.
+///
+/// SLIP is read a byte at a time, so `stream` ought to be buffered.
+pub fn read_frame<R: BufRead>(framing: Framing, stream: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    match framing {
+        Framing::Slip => {
+            let mut ret = vec![];
+            let mut escaped = false;
+            for byte in stream.bytes() {
+                let byte = byte?;
+                if escaped {
+                    match byte {
+                        SLIP_ESC_END => ret.push(SLIP_END),
+                        SLIP_ESC_ESC => ret.push(SLIP_ESC),
+                        b => {
+                            warn!("Invalid SLIP escape 0x{:02X}", b);
+                            ret.push(b);
+                        }
+                    }
+                    escaped = false;
+                    continue;
+                }
+                match byte {
+                    SLIP_END => {
+                        // Empty frames happen between double-ended packets; skip them.
+                        if !ret.is_empty() {
+                            return Ok(Some(ret));
+                        }
+                    },
+                    SLIP_ESC => escaped = true,
+                    b => ret.push(b)
+                }
+                if ret.len() > MAX_FRAME {
+                    bail!("frame too large (over {} bytes)", MAX_FRAME);
+                }
+            }
+            Ok(None)
+        },
+        Framing::Length => {
+            let mut len = [0u8; 4];
+            if let Err(e) = stream.read_exact(&mut len) {
+                if e.kind() == ::std::io::ErrorKind::UnexpectedEof {
+                    return Ok(None);
+                }
+                return Err(e.into());
+            }
+            let len = ((len[0] as usize) << 24) | ((len[1] as usize) << 16) | ((len[2] as usize) << 8) | (len[3] as usize);
+            if len > MAX_FRAME {
+                bail!("frame too large ({} bytes)", len);
+            }
+            let mut ret = vec![0u8; len];
+            stream.read_exact(&mut ret)?;
+            Ok(Some(ret))
+        }
+    }
+}
+
+pub struct TcpContext {
+    pub listener: TcpListener,
+    pub framing: Framing,
+    pub tx: Sender<Message>,
+    pub acl: Arc<AccessControl>,
+    /// Whether the audio engine is running (see `osc::process_msg`).
+    pub jack_up: Arc<AtomicBool>
+}
+impl TcpContext {
+    fn handle_connection(stream: TcpStream, framing: Framing, tx: Sender<Message>, acl: Arc<AccessControl>, jack_up: Arc<AtomicBool>) -> Result<(), Error> {
+        let addr = stream.peer_addr()?;
+        if !acl.source_allowed(addr.ip()) {
+            acl.reject(&format!("tcp:{}", addr), "source address not allowed");
+            return Ok(());
+        }
+        info!("Accepted TCP connection from {}", addr);
+        let writer = stream.try_clone()?;
+        writer.set_write_timeout(Some(Duration::from_millis(osc::STREAM_WRITE_TIMEOUT_MS)))?;
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let reply = Replier::Stream {
+            stream: Arc::new(Mutex::new(writer)),
+            format: StreamFormat::Osc(framing),
+            name: format!("tcp:{}", addr)
+        };
+        let mut reader = BufReader::new(stream);
+        while let Some(frame) = read_frame(framing, &mut reader)? {
+            info!("Received packet from tcp:{} (size: {})", addr, frame.len());
+            osc::process_packet(&tx, &acl, &jack_up, &frame, &reply);
+        }
+        info!("TCP connection from {} closed", addr);
+        Ok(())
+    }
+    pub fn run(&mut self) -> ! {
+        let connections = ConnectionCount::default();
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    let guard = match connections.acquire() {
+                        Some(g) => g,
+                        None => {
+                            warn!("Turning away TCP connection from {}: already handling {} connections", addr, osc::MAX_CONNECTIONS);
+                            continue;
+                        }
+                    };
+                    let framing = self.framing;
+                    let tx = self.tx.clone();
+                    let acl = self.acl.clone();
+                    let jack_up = self.jack_up.clone();
+                    ::std::thread::spawn(move || {
+                        let _guard = guard;
+                        if let Err(e) = Self::handle_connection(stream, framing, tx, acl, jack_up) {
+                            warn!("Error on TCP connection: {}", e);
+                        }
+                    });
+                },
+                Err(e) => {
+                    error!("Error accepting TCP connection: {}", e);
+                    ::std::thread::sleep(::std::time::Duration::from_millis(1000));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+
+    /// A stream that only gives out a byte at a time, like a slow connection.
+    struct Trickle(Cursor<Vec<u8>>);
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+    fn trickle(data: Vec<u8>) -> BufReader<Trickle> {
+        BufReader::new(Trickle(Cursor::new(data)))
+    }
+
+    #[test]
+    fn slip_escapes() {
+        let packet = [0x01, SLIP_END, 0x02, SLIP_ESC, 0x03, SLIP_ESC_END, SLIP_ESC_ESC];
+        let frame = encode_frame(Framing::Slip, &packet);
+        assert_eq!(frame, vec![SLIP_END, 0x01, SLIP_ESC, SLIP_ESC_END, 0x02, SLIP_ESC, SLIP_ESC_ESC,
+                               0x03, SLIP_ESC_END, SLIP_ESC_ESC, SLIP_END]);
+        let mut stream = Cursor::new(frame);
+        assert_eq!(read_frame(Framing::Slip, &mut stream).unwrap(), Some(packet.to_vec()));
+        assert_eq!(read_frame(Framing::Slip, &mut stream).unwrap(), None);
+    }
+    #[test]
+    fn slip_several_frames() {
+        let mut data = encode_frame(Framing::Slip, b"one");
+        data.extend(encode_frame(Framing::Slip, b"two"));
+        // Single-ended SLIP (no END at the start) works too.
+        data.extend_from_slice(b"three");
+        data.push(SLIP_END);
+        // An unknown escape is passed through as is.
+        data.extend_from_slice(&[SLIP_ESC, b'x', SLIP_END]);
+        let mut stream = trickle(data);
+        for packet in [&b"one"[..], b"two", b"three", b"x"].iter() {
+            assert_eq!(read_frame(Framing::Slip, &mut stream).unwrap(), Some(packet.to_vec()));
+        }
+        assert_eq!(read_frame(Framing::Slip, &mut stream).unwrap(), None);
+    }
+    #[test]
+    fn slip_unfinished_frame() {
+        let mut stream = Cursor::new(vec![SLIP_END, b'a', b'b']);
+        assert_eq!(read_frame(Framing::Slip, &mut stream).unwrap(), None);
+    }
+    #[test]
+    fn slip_too_large() {
+        let mut stream = Cursor::new(encode_frame(Framing::Slip, &vec![0x55; MAX_FRAME]));
+        assert_eq!(read_frame(Framing::Slip, &mut stream).unwrap().map(|f| f.len()), Some(MAX_FRAME));
+        let mut stream = Cursor::new(encode_frame(Framing::Slip, &vec![0x55; MAX_FRAME + 1]));
+        assert!(read_frame(Framing::Slip, &mut stream).is_err());
+    }
+    #[test]
+    fn length_prefix() {
+        let frame = encode_frame(Framing::Length, b"cake");
+        assert_eq!(frame, vec![0, 0, 0, 4, b'c', b'a', b'k', b'e']);
+        let mut data = frame;
+        // Nothing needs escaping with length-prefix framing.
+        data.extend(encode_frame(Framing::Length, &[SLIP_END, SLIP_ESC]));
+        data.extend(encode_frame(Framing::Length, &[]));
+        let mut stream = trickle(data);
+        assert_eq!(read_frame(Framing::Length, &mut stream).unwrap(), Some(b"cake".to_vec()));
+        assert_eq!(read_frame(Framing::Length, &mut stream).unwrap(), Some(vec![SLIP_END, SLIP_ESC]));
+        assert_eq!(read_frame(Framing::Length, &mut stream).unwrap(), Some(vec![]));
+        assert_eq!(read_frame(Framing::Length, &mut stream).unwrap(), None);
+    }
+    #[test]
+    fn length_prefix_truncated() {
+        // The stream ending partway through the length is just the end...
+        let mut stream = Cursor::new(vec![0, 0]);
+        assert_eq!(read_frame(Framing::Length, &mut stream).unwrap(), None);
+        // ...but partway through the packet is an error.
+        let mut stream = Cursor::new(vec![0, 0, 0, 8, b'a', b'b']);
+        assert!(read_frame(Framing::Length, &mut stream).is_err());
+    }
+    #[test]
+    fn length_prefix_too_large() {
+        let len = MAX_FRAME as u32 + 1;
+        let mut stream = Cursor::new(vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        assert!(read_frame(Framing::Length, &mut stream).is_err());
+        let mut stream = Cursor::new(vec![0xFF; 8]);
+        assert!(read_frame(Framing::Length, &mut stream).is_err());
+    }
+}
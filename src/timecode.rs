@@ -0,0 +1,918 @@
+//! Timecode (MTC or LTC): chasing external timecode, so that files can be
+//! started at particular points in a show, and generating timecode from a
+//! playing file.
+//!
+//! Like MIDI, these use their own JACK clients, since sqa-engine's one can't be
+//! given extra ports.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{Sender, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use jack::{self, Client, ClientOptions, ProcessScope, Control, Port, MidiIn, MidiOut, AudioIn, AudioOut, AsyncClient, RawMidi};
+use ctxt::{self, Message};
+use rt::{self, RtMessage, RtSender, RtReceiver};
+use config::{FrameRate, TimecodeConfig, TimecodeFormat, TimecodeOutConfig, PlaybackFile};
+use failure::Error;
+
+/// The name of the JACK client used for chasing timecode.
+pub const CLIENT_NAME: &str = "mfl-gramophone-timecode";
+/// The name of the JACK client used for sending timecode.
+pub const OUTPUT_CLIENT_NAME: &str = "mfl-gramophone-timecode-out";
+
+/// A timecode value, `HH:MM:SS:FF`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8
+}
+impl Timecode {
+    /// Parses a timecode written as `HH:MM:SS:FF` (a `;` or `.` before the frames
+    /// is also accepted).
+    pub fn parse(s: &str, rate: FrameRate) -> Result<Self, Error> {
+        let parts = s.trim().split(&[':', ';', '.'][..])
+            .map(|x| x.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format_err!("invalid timecode '{}': {}", s, e))?;
+        if parts.len() != 4 {
+            bail!("invalid timecode '{}': expected HH:MM:SS:FF", s);
+        }
+        let ret = Timecode {
+            hours: parts[0],
+            minutes: parts[1],
+            seconds: parts[2],
+            frames: parts[3]
+        };
+        if ret.hours > 23 || ret.minutes > 59 || ret.seconds > 59 || ret.frames as u64 >= rate.fps() {
+            bail!("invalid timecode '{}': out of range", s);
+        }
+        Ok(ret)
+    }
+    /// The number of frames since midnight.
+    pub fn to_frames(&self, rate: FrameRate) -> u64 {
+        let secs = (self.hours as u64 * 60 + self.minutes as u64) * 60 + self.seconds as u64;
+        let mut frames = secs * rate.fps() + self.frames as u64;
+        if rate.is_drop_frame() {
+            // Frames 0 and 1 are skipped every minute, apart from every tenth one.
+            let mins = self.hours as u64 * 60 + self.minutes as u64;
+            frames -= 2 * (mins - mins / 10);
+        }
+        frames
+    }
+    /// The reverse of `to_frames`.
+    pub fn from_frames(mut frames: u64, rate: FrameRate) -> Self {
+        let fps = rate.fps();
+        if rate.is_drop_frame() {
+            let tens = frames / 17982;
+            let rem = frames % 17982;
+            frames += 18 * tens;
+            if rem >= 2 {
+                frames += 2 * ((rem - 2) / 1798);
+            }
+        }
+        let secs = frames / fps;
+        Timecode {
+            hours: ((secs / 3600) % 24) as u8,
+            minutes: ((secs / 60) % 60) as u8,
+            seconds: (secs % 60) as u8,
+            frames: (frames % fps) as u8
+        }
+    }
+    /// Converts this timecode to milliseconds since midnight.
+    pub fn to_ms(&self, rate: FrameRate) -> u64 {
+        frames_to_ms(self.to_frames(rate), rate)
+    }
+    /// Converts milliseconds since midnight to a timecode.
+    pub fn from_ms(ms: u64, rate: FrameRate) -> Self {
+        Self::from_frames(ms_to_frames(ms, rate), rate)
+    }
+}
+/// The actual number of frames per second.
+pub fn real_fps(rate: FrameRate) -> f64 {
+    if rate.is_drop_frame() {
+        30000.0 / 1001.0
+    }
+    else {
+        rate.fps() as f64
+    }
+}
+/// Converts a number of frames to milliseconds.
+///
+/// This rounds up, to the first whole millisecond within the frame, so that
+/// `ms_to_frames` gives the same frame back.
+pub fn frames_to_ms(frames: u64, rate: FrameRate) -> u64 {
+    if rate.is_drop_frame() {
+        (frames * 1001).div_ceil(30)
+    }
+    else {
+        (frames * 1000).div_ceil(rate.fps())
+    }
+}
+/// Converts milliseconds to a (whole) number of frames.
+pub fn ms_to_frames(ms: u64, rate: FrameRate) -> u64 {
+    if rate.is_drop_frame() {
+        ms * 30 / 1001
+    }
+    else {
+        ms * rate.fps() / 1000
+    }
+}
+/// The MTC / LTC code for a frame rate.
+pub fn rate_code(rate: FrameRate) -> u8 {
+    match rate {
+        FrameRate::Fps24 => 0,
+        FrameRate::Fps25 => 1,
+        FrameRate::Fps30Drop => 2,
+        FrameRate::Fps30 => 3
+    }
+}
+fn rate_from_code(code: u8) -> FrameRate {
+    match code & 0x03 {
+        0 => FrameRate::Fps24,
+        1 => FrameRate::Fps25,
+        2 => FrameRate::Fps30Drop,
+        _ => FrameRate::Fps30
+    }
+}
+
+/// Puts MIDI timecode back together from quarter-frame and full-frame messages.
+#[derive(Default)]
+struct MtcDecoder {
+    /// The nibbles received so far, indexed by piece number.
+    pieces: [u8; 8],
+    /// Which pieces have been received since the last complete timecode.
+    received: u8
+}
+impl MtcDecoder {
+    /// Feeds in a MIDI message, returning the current position (in milliseconds)
+    /// if it's now known.
+    fn feed(&mut self, bytes: &[u8]) -> Option<u64> {
+        match bytes {
+            [0xF1, data] => {
+                let piece = (data >> 4) & 0x07;
+                if piece == 0 {
+                    self.received = 0;
+                }
+                self.pieces[piece as usize] = data & 0x0F;
+                self.received |= 1 << piece;
+                if piece != 7 || self.received != 0xFF {
+                    return None;
+                }
+                let p = &self.pieces;
+                let rate = rate_from_code(p[7] >> 1);
+                let tc = Timecode {
+                    frames: p[0] | ((p[1] & 0x01) << 4),
+                    seconds: p[2] | ((p[3] & 0x03) << 4),
+                    minutes: p[4] | ((p[5] & 0x03) << 4),
+                    hours: p[6] | ((p[7] & 0x01) << 4)
+                };
+                // The time in quarter-frames refers to when piece 0 was sent,
+                // which was two frames ago.
+                Some(frames_to_ms(tc.to_frames(rate) + 2, rate))
+            },
+            [0xF0, 0x7F, _, 0x01, 0x01, hr, mn, sc, fr, 0xF7] => {
+                let rate = rate_from_code(hr >> 5);
+                let tc = Timecode {
+                    hours: hr & 0x1F,
+                    minutes: *mn,
+                    seconds: *sc,
+                    frames: *fr
+                };
+                self.received = 0;
+                Some(tc.to_ms(rate))
+            },
+            _ => None
+        }
+    }
+}
+
+/// LTC sync word, as it appears in bits 64-79 of a frame.
+const LTC_SYNC: u128 = 0xBFFC;
+
+/// Decodes LTC (biphase mark code) from audio samples.
+struct LtcDecoder {
+    rate: FrameRate,
+    /// Estimated length of one bit, in samples.
+    bit_len: f32,
+    /// Samples since the last transition.
+    since: u32,
+    /// Whether the signal is currently high.
+    high: bool,
+    /// Whether we've seen the first half of a 1 bit.
+    half: bool,
+    /// The last 80 bits received; the newest is bit 79.
+    bits: u128
+}
+impl LtcDecoder {
+    /// Signal level, either side of zero, needed to count as a transition.
+    const THRESHOLD: f32 = 0.02;
+
+    fn new(rate: FrameRate, sample_rate: usize) -> Self {
+        LtcDecoder {
+            rate,
+            bit_len: sample_rate as f32 / (rate.fps() as f32 * 80.0),
+            since: 0,
+            high: false,
+            half: false,
+            bits: 0
+        }
+    }
+    fn push_bit(&mut self, bit: bool) -> Option<u64> {
+        self.bits = (self.bits >> 1) | ((bit as u128) << 79);
+        if (self.bits >> 64) & 0xFFFF != LTC_SYNC {
+            return None;
+        }
+        let b = |start: u32, len: u32| ((self.bits >> start) & ((1 << len) - 1)) as u8;
+        let tc = Timecode {
+            frames: b(0, 4) + b(8, 2) * 10,
+            seconds: b(16, 4) + b(24, 3) * 10,
+            minutes: b(32, 4) + b(40, 3) * 10,
+            hours: b(48, 4) + b(56, 2) * 10
+        };
+        // The frame has just finished, so we're one frame further on.
+        Some(frames_to_ms(tc.to_frames(self.rate) + 1, self.rate))
+    }
+    /// Feeds in some samples, returning the position (in milliseconds) of the last
+    /// complete frame in them, if there was one.
+    fn feed(&mut self, samples: &[f32]) -> Option<u64> {
+        let mut ret = None;
+        for &s in samples.iter() {
+            self.since += 1;
+            let flipped = if self.high { s < -Self::THRESHOLD } else { s > Self::THRESHOLD };
+            if !flipped {
+                continue;
+            }
+            self.high = !self.high;
+            let len = self.since as f32;
+            self.since = 0;
+            if len < self.bit_len * 0.75 {
+                // Half a bit: two of these make a 1.
+                if self.half {
+                    self.half = false;
+                    self.bit_len = self.bit_len * 0.9 + len * 2.0 * 0.1;
+                    if let Some(pos) = self.push_bit(true) {
+                        ret = Some(pos);
+                    }
+                }
+                else {
+                    self.half = true;
+                }
+            }
+            else {
+                self.half = false;
+                self.bit_len = self.bit_len * 0.9 + len * 0.1;
+                if let Some(pos) = self.push_bit(false) {
+                    ret = Some(pos);
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// Decodes timecode in the JACK process callback.
+enum TimecodeProcess {
+    Mtc {
+        port: Port<MidiIn>,
+        decoder: MtcDecoder,
+        tx: RtSender<u64>
+    },
+    Ltc {
+        port: Port<AudioIn>,
+        decoder: LtcDecoder,
+        tx: RtSender<u64>
+    }
+}
+impl jack::ProcessHandler for TimecodeProcess {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        match *self {
+            TimecodeProcess::Mtc { ref port, ref mut decoder, ref mut tx } => {
+                for ev in port.iter(ps) {
+                    if let Some(pos) = decoder.feed(ev.bytes) {
+                        tx.send(&pos);
+                    }
+                }
+            },
+            TimecodeProcess::Ltc { ref port, ref mut decoder, ref mut tx } => {
+                if let Some(pos) = decoder.feed(port.as_slice(ps)) {
+                    tx.send(&pos);
+                }
+            }
+        }
+        Control::Continue
+    }
+}
+/// How many decoded positions can be waiting for the worker thread at once.
+const QUEUE_LEN: usize = 64;
+/// A running timecode input. Dropping this deactivates the JACK client.
+pub struct TimecodeInput {
+    _client: AsyncClient<(), TimecodeProcess>
+}
+
+/// The state of timecode chasing, kept by the `Context`.
+pub struct Chase {
+    /// Files with trigger points, and where those are (in milliseconds).
+    pub triggers: Vec<(String, u64)>,
+    pub tolerance_ms: u64,
+    pub level: f64,
+    /// Files that are being chased, and when they were last located.
+    pub chased: HashMap<String, Instant>
+}
+impl Chase {
+    /// How long to wait after locating a file before checking whether it's drifted.
+    pub const SETTLE_MS: u64 = 1000;
+
+    pub fn new(cfg: &TimecodeConfig, files: &HashMap<String, PlaybackFile>) -> Result<Self, Error> {
+        let mut triggers = vec![];
+        for (name, pf) in files.iter() {
+            if let Some(ref tc) = pf.timecode {
+                let tc = Timecode::parse(tc, cfg.frame_rate)
+                    .map_err(|e| format_err!("file '{}': {}", name, e))?;
+                triggers.push((name.clone(), tc.to_ms(cfg.frame_rate)));
+            }
+        }
+        triggers.sort();
+        Ok(Chase {
+            triggers,
+            tolerance_ms: cfg.tolerance_ms,
+            level: cfg.level,
+            chased: HashMap::new()
+        })
+    }
+}
+
+/// Passes positions on to the `Context`, and tells it when timecode stops.
+fn run_worker(mut rx: RtReceiver<u64>, tx: Sender<Message>, freewheel: Duration) {
+    let mut running = false;
+    loop {
+        match rx.recv_timeout(freewheel) {
+            Ok(pos) => {
+                if !running {
+                    info!("Timecode started");
+                    running = true;
+                }
+                if tx.send(Message::Timecode(Some(pos))).is_err() {
+                    break;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {
+                if running {
+                    info!("Timecode stopped");
+                    running = false;
+                    if tx.send(Message::Timecode(None)).is_err() {
+                        break;
+                    }
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+    }
+}
+
+/// Creates the JACK timecode input, connects it up, and starts chasing.
+pub fn start(cfg: &TimecodeConfig, tx: Sender<Message>) -> Result<TimecodeInput, Error> {
+    let (client, _) = Client::new(CLIENT_NAME, ClientOptions::NO_START_SERVER)
+        .map_err(|e| format_err!("failed creating JACK client: {:?}", e))?;
+    let (ttx, trx) = rt::channel(QUEUE_LEN)?;
+    let (process, port_name) = match cfg.source {
+        TimecodeFormat::Mtc => {
+            let port = client.register_port("mtc_in", MidiIn)
+                .map_err(|e| format_err!("failed registering MTC port: {:?}", e))?;
+            let name = port.name()
+                .map_err(|e| format_err!("failed getting MTC port name: {:?}", e))?;
+            (TimecodeProcess::Mtc { port, decoder: MtcDecoder::default(), tx: ttx }, name)
+        },
+        TimecodeFormat::Ltc => {
+            let port = client.register_port("ltc_in", AudioIn)
+                .map_err(|e| format_err!("failed registering LTC port: {:?}", e))?;
+            let name = port.name()
+                .map_err(|e| format_err!("failed getting LTC port name: {:?}", e))?;
+            let decoder = LtcDecoder::new(cfg.frame_rate, client.sample_rate());
+            (TimecodeProcess::Ltc { port, decoder, tx: ttx }, name)
+        }
+    };
+    let client = client.activate_async((), process)
+        .map_err(|e| format_err!("failed activating JACK client: {:?}", e))?;
+    for src in cfg.connect.iter() {
+        info!("[+] Connecting timecode input to {}", src);
+        if let Err(e) = client.as_client().connect_ports_by_name(src, &port_name) {
+            warn!("Failed connecting timecode input to {}: {:?}", src, e);
+        }
+    }
+    let freewheel = Duration::from_millis(cfg.freewheel_ms);
+    ::std::thread::spawn(move || {
+        run_worker(trx, tx, freewheel);
+    });
+    Ok(TimecodeInput {
+        _client: client
+    })
+}
+
+/// Encodes the LTC frame for `tc`, in the same bit order `LtcDecoder` uses.
+fn encode_ltc(tc: Timecode, rate: FrameRate) -> u128 {
+    let mut bits = 0u128;
+    {
+        let mut put = |start: u32, val: u8| bits |= (val as u128) << start;
+        put(0, tc.frames % 10);
+        put(8, tc.frames / 10);
+        put(10, rate.is_drop_frame() as u8);
+        put(16, tc.seconds % 10);
+        put(24, tc.seconds / 10);
+        put(32, tc.minutes % 10);
+        put(40, tc.minutes / 10);
+        put(48, tc.hours % 10);
+        put(56, tc.hours / 10);
+    }
+    bits |= LTC_SYNC << 64;
+    // The polarity correction bit makes the number of zeros in a frame even.
+    let polarity_bit = if rate == FrameRate::Fps25 { 59 } else { 27 };
+    if (80 - bits.count_ones()) % 2 == 1 {
+        bits |= 1 << polarity_bit;
+    }
+    bits
+}
+
+/// Commands sent to the timecode generator.
+enum GeneratorCommand {
+    /// Start generating timecode, from `ms` milliseconds since midnight.
+    Start {
+        format: TimecodeFormat,
+        rate: FrameRate,
+        ms: u64
+    },
+    /// The timecode should be at `ms` milliseconds since midnight now (see
+    /// `Generator::sync`).
+    Sync {
+        ms: u64
+    },
+    Stop
+}
+impl RtMessage for GeneratorCommand {
+    const SIZE: usize = 16;
+    fn encode(&self, buf: &mut [u8]) {
+        match *self {
+            GeneratorCommand::Start { format, rate, ms } => {
+                buf[0] = 1;
+                buf[1] = (format == TimecodeFormat::Ltc) as u8;
+                buf[2] = rate_code(rate);
+                ms.encode(&mut buf[3..11]);
+            },
+            GeneratorCommand::Sync { ms } => {
+                buf[0] = 2;
+                ms.encode(&mut buf[3..11]);
+            },
+            GeneratorCommand::Stop => buf[0] = 0
+        }
+    }
+    fn decode(buf: &[u8]) -> Option<Self> {
+        match buf[0] {
+            0 => Some(GeneratorCommand::Stop),
+            1 => Some(GeneratorCommand::Start {
+                format: if buf[1] == 1 { TimecodeFormat::Ltc } else { TimecodeFormat::Mtc },
+                rate: rate_from_code(buf[2]),
+                ms: u64::decode(&buf[3..11])?
+            }),
+            2 => Some(GeneratorCommand::Sync {
+                ms: u64::decode(&buf[3..11])?
+            }),
+            _ => None
+        }
+    }
+}
+/// How many commands can be waiting for the timecode generator at once.
+const COMMAND_QUEUE_LEN: usize = 16;
+/// How far, in frames, the generated timecode can get from the playback
+/// position before it's made to jump back into step.
+const SYNC_TOLERANCE_FRAMES: f64 = 2.0;
+/// Generates timecode, in the JACK process callback.
+struct Generator {
+    format: TimecodeFormat,
+    rate: FrameRate,
+    /// The current frame (counted from midnight).
+    frame: u64,
+    /// How far through the current frame we are, from 0 to 1.
+    frac: f64,
+    /// How much `frac` advances each sample.
+    step: f64,
+    /// The MTC quarter-frame (0-3) or LTC half-bit (0-159) we're currently in.
+    part: u32,
+    /// The frame the current sequence of 8 MTC quarter-frames started on.
+    seq_start: u64,
+    /// Whether the MTC full-frame message still needs sending.
+    send_full: bool,
+    /// The current LTC frame, and signal polarity.
+    bits: u128,
+    high: bool
+}
+impl Generator {
+    fn new(format: TimecodeFormat, rate: FrameRate, ms: u64, sample_rate: usize) -> Self {
+        let frames = ms as f64 * real_fps(rate) / 1000.0;
+        let frame = frames as u64;
+        Generator {
+            format, rate, frame,
+            frac: frames - frame as f64,
+            step: real_fps(rate) / sample_rate as f64,
+            part: u32::MAX,
+            seq_start: frame,
+            send_full: true,
+            bits: encode_ltc(Timecode::from_frames(frame, rate), rate),
+            high: false
+        }
+    }
+    /// Jumps to `ms` milliseconds since midnight, if that's far enough from
+    /// where we are that it isn't just jitter (e.g. because a looping file has
+    /// gone back to the start, or playback has fallen behind).
+    fn sync(&mut self, ms: u64, sample_rate: usize) {
+        let want = ms as f64 * real_fps(self.rate) / 1000.0;
+        let have = self.frame as f64 + self.frac;
+        if (want - have).abs() > SYNC_TOLERANCE_FRAMES {
+            *self = Generator::new(self.format, self.rate, ms, sample_rate);
+        }
+    }
+    /// The MTC quarter-frame message for quarter `quarter` of the current frame.
+    fn quarter_frame(&self, quarter: u32) -> [u8; 2] {
+        let second = (self.frame - self.seq_start) % 2 == 1;
+        let tc = Timecode::from_frames(if second { self.frame - 1 } else { self.frame }, self.rate);
+        let piece = quarter as u8 + if second { 4 } else { 0 };
+        let nibble = match piece {
+            0 => tc.frames & 0x0F,
+            1 => tc.frames >> 4,
+            2 => tc.seconds & 0x0F,
+            3 => tc.seconds >> 4,
+            4 => tc.minutes & 0x0F,
+            5 => tc.minutes >> 4,
+            6 => tc.hours & 0x0F,
+            _ => (tc.hours >> 4) | (rate_code(self.rate) << 1)
+        };
+        [0xF1, (piece << 4) | nibble]
+    }
+    fn full_frame(&self) -> [u8; 10] {
+        let tc = Timecode::from_frames(self.frame, self.rate);
+        [0xF0, 0x7F, 0x7F, 0x01, 0x01, (rate_code(self.rate) << 5) | tc.hours, tc.minutes, tc.seconds, tc.frames, 0xF7]
+    }
+    /// Fills in one process cycle's worth of timecode.
+    fn generate(&mut self, nframes: usize, midi: &mut jack::MidiWriter, audio: &mut [f32], level: f32) {
+        for i in 0..nframes {
+            match self.format {
+                TimecodeFormat::Mtc => {
+                    if self.send_full {
+                        self.send_full = false;
+                        let _ = midi.write(&RawMidi { time: i as u32, bytes: &self.full_frame() });
+                    }
+                    let quarter = (self.frac * 4.0) as u32;
+                    if quarter != self.part {
+                        self.part = quarter;
+                        let msg = self.quarter_frame(quarter);
+                        let _ = midi.write(&RawMidi { time: i as u32, bytes: &msg });
+                    }
+                },
+                TimecodeFormat::Ltc => {
+                    // Biphase mark: flip at the start of every bit, and in the
+                    // middle of 1 bits.
+                    let half = (self.frac * 160.0) as u32;
+                    if half != self.part {
+                        self.part = half;
+                        if half & 1 == 0 || (self.bits >> (half / 2)) & 1 == 1 {
+                            self.high = !self.high;
+                        }
+                    }
+                    if let Some(s) = audio.get_mut(i) {
+                        *s = if self.high { level } else { -level };
+                    }
+                }
+            }
+            self.frac += self.step;
+            if self.frac >= 1.0 {
+                self.frac -= 1.0;
+                self.frame += 1;
+                self.bits = encode_ltc(Timecode::from_frames(self.frame, self.rate), self.rate);
+            }
+        }
+    }
+}
+/// Runs the timecode generator in the JACK process callback.
+struct OutputProcess {
+    mtc: Port<MidiOut>,
+    ltc: Port<AudioOut>,
+    /// Linear amplitude of the LTC signal.
+    ltc_level: f32,
+    sample_rate: usize,
+    rx: RtReceiver<GeneratorCommand>,
+    generator: Option<Generator>
+}
+impl jack::ProcessHandler for OutputProcess {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        while let Some(cmd) = self.rx.try_recv() {
+            match cmd {
+                GeneratorCommand::Start { format, rate, ms } => {
+                    self.generator = Some(Generator::new(format, rate, ms, self.sample_rate));
+                },
+                GeneratorCommand::Sync { ms } => {
+                    if let Some(ref mut gen) = self.generator {
+                        gen.sync(ms, self.sample_rate);
+                    }
+                },
+                GeneratorCommand::Stop => self.generator = None
+            }
+        }
+        let mut midi = self.mtc.writer(ps);
+        let audio = self.ltc.as_mut_slice(ps);
+        for s in audio.iter_mut() {
+            *s = 0.0;
+        }
+        if let Some(ref mut gen) = self.generator {
+            gen.generate(ps.n_frames() as usize, &mut midi, audio, self.ltc_level);
+        }
+        Control::Continue
+    }
+}
+/// Sends timecode while files with `timecode_out` set are playing.
+///
+/// Only one file can drive the output at once; the one started most recently
+/// wins.
+pub struct TimecodeOutput {
+    _client: AsyncClient<(), OutputProcess>,
+    tx: RtSender<GeneratorCommand>,
+    /// For each file with timecode output: its format, frame rate, and the
+    /// timecode at its start (in milliseconds).
+    files: HashMap<String, (TimecodeFormat, FrameRate, u64)>,
+    /// The file currently driving the output.
+    current: Option<String>
+}
+impl TimecodeOutput {
+    /// Starts sending timecode for `file`, if it has timecode output, from
+    /// `position_ms` milliseconds into it.
+    pub fn start_file(&mut self, file: &str, position_ms: u64) {
+        if let Some(&(format, rate, start_ms)) = self.files.get(file) {
+            info!("Sending {:?} from file '{}'", format, file);
+            if !self.tx.send(&GeneratorCommand::Start { format, rate, ms: start_ms + position_ms }) {
+                warn!("Timecode output is lagging; not sending timecode from file '{}'", file);
+                return;
+            }
+            self.current = Some(file.into());
+        }
+    }
+    /// Keeps the timecode in step with `file`'s actual playback position
+    /// (`position_ms` into it), if it's what's driving the output.
+    pub fn sync_file(&mut self, file: &str, position_ms: u64) {
+        if self.current.as_ref().map(|x| x as &str) != Some(file) {
+            return;
+        }
+        if let Some(&(_, _, start_ms)) = self.files.get(file) {
+            // If this gets dropped because the generator's lagging, the next
+            // one will do.
+            self.tx.send(&GeneratorCommand::Sync { ms: start_ms + position_ms });
+        }
+    }
+    /// Replaces the set of files with timecode output (e.g. after a reload).
+    pub fn set_files(&mut self, files: HashMap<String, (TimecodeFormat, FrameRate, u64)>) {
+        self.files = files;
+    }
+    /// Stops sending timecode, if `file` is what's driving it.
+    pub fn stop_file(&mut self, file: &str) {
+        if self.current.as_ref().map(|x| x as &str) == Some(file) {
+            info!("Stopping timecode from file '{}'", file);
+            if !self.tx.send(&GeneratorCommand::Stop) {
+                warn!("Timecode output is lagging; couldn't stop it");
+            }
+            self.current = None;
+        }
+    }
+}
+
+/// Works out the format, frame rate and start time of every file in `files`
+/// with timecode output.
+pub fn output_files(files: &HashMap<String, PlaybackFile>) -> Result<HashMap<String, (TimecodeFormat, FrameRate, u64)>, Error> {
+    let mut ret = HashMap::new();
+    for (name, pf) in files.iter() {
+        if let Some(ref out) = pf.timecode_out {
+            let start_ms = match out.start {
+                Some(ref s) => Timecode::parse(s, out.frame_rate)
+                    .map_err(|e| format_err!("file '{}': {}", name, e))?
+                    .to_ms(out.frame_rate),
+                None => 0
+            };
+            ret.insert(name.clone(), (out.format, out.frame_rate, start_ms));
+        }
+    }
+    Ok(ret)
+}
+/// Creates the JACK timecode output, and connects it up.
+pub fn start_output(cfg: &TimecodeOutConfig, files: &HashMap<String, PlaybackFile>) -> Result<TimecodeOutput, Error> {
+    let out_files = output_files(files)?;
+    let (client, _) = Client::new(OUTPUT_CLIENT_NAME, ClientOptions::NO_START_SERVER)
+        .map_err(|e| format_err!("failed creating JACK client: {:?}", e))?;
+    let mtc = client.register_port("mtc_out", MidiOut)
+        .map_err(|e| format_err!("failed registering MTC port: {:?}", e))?;
+    let ltc = client.register_port("ltc_out", AudioOut)
+        .map_err(|e| format_err!("failed registering LTC port: {:?}", e))?;
+    let mtc_name = mtc.name()
+        .map_err(|e| format_err!("failed getting MTC port name: {:?}", e))?;
+    let ltc_name = ltc.name()
+        .map_err(|e| format_err!("failed getting LTC port name: {:?}", e))?;
+    let (tx, rx) = rt::channel(COMMAND_QUEUE_LEN)?;
+    let process = OutputProcess {
+        mtc, ltc, rx,
+        ltc_level: ctxt::db_lin(cfg.ltc_level) as f32,
+        sample_rate: client.sample_rate(),
+        generator: None
+    };
+    let client = client.activate_async((), process)
+        .map_err(|e| format_err!("failed activating JACK client: {:?}", e))?;
+    for &(name, dests) in [(&mtc_name, &cfg.mtc_connect), (&ltc_name, &cfg.ltc_connect)].iter() {
+        for dest in dests.iter() {
+            info!("[+] Connecting {} to {}", name, dest);
+            if let Err(e) = client.as_client().connect_ports_by_name(name, dest) {
+                warn!("Failed connecting {} to {}: {:?}", name, dest, e);
+            }
+        }
+    }
+    Ok(TimecodeOutput {
+        _client: client,
+        files: out_files,
+        current: None,
+        tx
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATES: [FrameRate; 4] = [FrameRate::Fps24, FrameRate::Fps25, FrameRate::Fps30Drop, FrameRate::Fps30];
+
+    fn tc(hours: u8, minutes: u8, seconds: u8, frames: u8) -> Timecode {
+        Timecode { hours, minutes, seconds, frames }
+    }
+    /// Some timecodes to try, valid at every frame rate (including drop-frame).
+    fn samples() -> Vec<Timecode> {
+        vec![tc(0, 0, 0, 0), tc(0, 0, 59, 23), tc(0, 1, 0, 2), tc(0, 9, 59, 23),
+             tc(0, 10, 0, 0), tc(1, 23, 45, 17), tc(12, 34, 56, 7), tc(23, 59, 59, 23)]
+    }
+    #[test]
+    fn frames_round_trip() {
+        for &rate in RATES.iter() {
+            // Eleven minutes, to get past the first ten-minute drop-frame cycle.
+            for n in 0..(11 * 60 * 30) {
+                assert_eq!(Timecode::from_frames(n, rate).to_frames(rate), n, "{:?} frame {}", rate, n);
+            }
+            for t in samples() {
+                assert_eq!(Timecode::from_frames(t.to_frames(rate), rate), t, "{:?}", rate);
+                assert_eq!(Timecode::from_ms(t.to_ms(rate), rate), t, "{:?}", rate);
+            }
+        }
+    }
+    #[test]
+    fn ms_round_trip() {
+        for &rate in RATES.iter() {
+            for n in 0..(11 * 60 * 30) {
+                assert_eq!(ms_to_frames(frames_to_ms(n, rate), rate), n, "{:?} frame {}", rate, n);
+            }
+        }
+    }
+    #[test]
+    fn drop_frame_labels() {
+        let rate = FrameRate::Fps30Drop;
+        // Frames 0 and 1 are skipped at the start of each minute...
+        let n = tc(0, 0, 59, 29).to_frames(rate);
+        assert_eq!(Timecode::from_frames(n + 1, rate), tc(0, 1, 0, 2));
+        // ...apart from every tenth one.
+        let n = tc(0, 9, 59, 29).to_frames(rate);
+        assert_eq!(n, 17981);
+        assert_eq!(Timecode::from_frames(n + 1, rate), tc(0, 10, 0, 0));
+        assert_eq!(tc(0, 10, 0, 0).to_frames(rate), 17982);
+        assert_eq!(Timecode::from_frames(17982 + 1800, rate), tc(0, 11, 0, 2));
+    }
+    #[test]
+    fn ltc_frame_layout() {
+        for &rate in RATES.iter() {
+            let mut corrected = false;
+            for t in samples() {
+                let bits = encode_ltc(t, rate);
+                assert_eq!((bits >> 64) & 0xFFFF, 0xBFFC, "{:?} sync word", rate);
+                assert_eq!(bits >> 80, 0);
+                assert_eq!((80 - bits.count_ones()) % 2, 0, "{:?} {:?} has an odd number of zeros", rate, t);
+                assert_eq!((bits >> 10) & 1 == 1, rate.is_drop_frame());
+                // At 25fps the polarity bit is bit 59, and bit 27 is a binary
+                // group flag; at the other rates, it's the other way round.
+                let (polarity, flag) = if rate == FrameRate::Fps25 { (59, 27) } else { (27, 59) };
+                assert_eq!((bits >> flag) & 1, 0, "{:?} {:?}", rate, t);
+                corrected |= (bits >> polarity) & 1 == 1;
+            }
+            assert!(corrected, "{:?}: the polarity bit was never needed", rate);
+        }
+    }
+    #[test]
+    fn ltc_bits_round_trip() {
+        for &rate in RATES.iter() {
+            let mut times = samples();
+            if rate.is_drop_frame() {
+                times.push(tc(0, 9, 59, 29));
+            }
+            for t in times {
+                let mut dec = LtcDecoder::new(rate, 48000);
+                let bits = encode_ltc(t, rate);
+                let mut ret = None;
+                for i in 0..80 {
+                    ret = dec.push_bit((bits >> i) & 1 == 1);
+                }
+                assert_eq!(ret, Some(frames_to_ms(t.to_frames(rate) + 1, rate)), "{:?} {:?}", rate, t);
+            }
+        }
+        // The decoder's position is for the frame after the one just read.
+        let rate = FrameRate::Fps30Drop;
+        let mut dec = LtcDecoder::new(rate, 48000);
+        let bits = encode_ltc(tc(0, 9, 59, 29), rate);
+        let mut ret = None;
+        for i in 0..80 {
+            ret = dec.push_bit((bits >> i) & 1 == 1);
+        }
+        assert_eq!(ret.map(|ms| Timecode::from_ms(ms, rate)), Some(tc(0, 10, 0, 0)));
+    }
+    /// Biphase-mark-encodes `bits`, at 48kHz, continuing from `high`.
+    fn modulate(bits: u128, rate: FrameRate, high: &mut bool, out: &mut Vec<f32>) {
+        let bit_len = 48000 / (rate.fps() as usize * 80);
+        for i in 0..80 {
+            for s in 0..bit_len {
+                if s == 0 || (s == bit_len / 2 && (bits >> i) & 1 == 1) {
+                    *high = !*high;
+                }
+                out.push(if *high { 0.5 } else { -0.5 });
+            }
+        }
+    }
+    #[test]
+    fn ltc_audio_round_trip() {
+        for &rate in RATES.iter() {
+            let start = tc(0, 9, 59, 26).to_frames(rate);
+            let mut dec = LtcDecoder::new(rate, 48000);
+            let mut high = false;
+            for n in start..(start + 6) {
+                let mut samples = vec![];
+                modulate(encode_ltc(Timecode::from_frames(n, rate), rate), rate, &mut high, &mut samples);
+                let ret = dec.feed(&samples);
+                // The last bit of a frame is only finished when the next one
+                // starts, so each frame is read while the next one's being fed
+                // in; by then the decoder should have locked on.
+                if n >= start + 2 {
+                    assert_eq!(ret, Some(frames_to_ms(n, rate)), "{:?} frame {}", rate, n);
+                }
+            }
+        }
+    }
+    #[test]
+    fn mtc_nibble_order() {
+        let rate = FrameRate::Fps25;
+        let mut gen = Generator::new(TimecodeFormat::Mtc, rate, 0, 48000);
+        gen.frame = tc(1, 23, 45, 17).to_frames(rate);
+        gen.seq_start = gen.frame;
+        let mut msgs = (0..4).map(|q| gen.quarter_frame(q)).collect::<Vec<_>>();
+        gen.frame += 1;
+        msgs.extend((0..4).map(|q| gen.quarter_frame(q)));
+        // Frames, seconds, minutes, then hours (with the rate code), low nibble first.
+        let expected = [0x01, 0x11, 0x2D, 0x32, 0x47, 0x51, 0x61, 0x72];
+        for (msg, &data) in msgs.iter().zip(expected.iter()) {
+            assert_eq!(*msg, [0xF1, data]);
+        }
+        assert_eq!(gen.full_frame(), [0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x21, 23, 45, 18, 0xF7]);
+    }
+    #[test]
+    fn mtc_round_trip() {
+        for &rate in RATES.iter() {
+            let mut times = samples();
+            if rate.is_drop_frame() {
+                times.push(tc(0, 9, 59, 28));
+            }
+            for t in times {
+                let mut gen = Generator::new(TimecodeFormat::Mtc, rate, 0, 48000);
+                gen.frame = t.to_frames(rate);
+                gen.seq_start = gen.frame;
+                let mut dec = MtcDecoder::default();
+                assert_eq!(dec.feed(&gen.full_frame()), Some(t.to_ms(rate)), "{:?} {:?}", rate, t);
+                let mut ret = None;
+                for _ in 0..2 {
+                    for q in 0..4 {
+                        ret = dec.feed(&gen.quarter_frame(q));
+                    }
+                    gen.frame += 1;
+                }
+                // By the time all eight quarter-frames are in, two frames have gone by.
+                assert_eq!(ret, Some(frames_to_ms(t.to_frames(rate) + 2, rate)), "{:?} {:?}", rate, t);
+            }
+        }
+        let rate = FrameRate::Fps30Drop;
+        let mut gen = Generator::new(TimecodeFormat::Mtc, rate, 0, 48000);
+        gen.frame = tc(0, 9, 59, 28).to_frames(rate);
+        gen.seq_start = gen.frame;
+        let mut dec = MtcDecoder::default();
+        let mut ret = None;
+        for _ in 0..2 {
+            for q in 0..4 {
+                ret = dec.feed(&gen.quarter_frame(q));
+            }
+            gen.frame += 1;
+        }
+        assert_eq!(ret.map(|ms| Timecode::from_ms(ms, rate)), Some(tc(0, 10, 0, 0)));
+    }
+}
@@ -0,0 +1,279 @@
+//! Control over a Unix domain socket, carrying either OSC or lines of text.
+//!
+//! Text commands look like OSC messages written out by hand, e.g.
+//! `/file/cake/start -6`. Arguments are separated by whitespace, and are
+//! treated as integers, floats, booleans (`true` / `false`) or strings, in that
+//! order of preference; put an argument in double quotes to force it to be a
+//! string (or to include spaces in it).
+
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use std::fs;
+use rosc::{OscMessage, OscType, self};
+use ctxt::Message;
+use config::{Framing, UnixKind};
+use osc::{self, Replier, StreamFormat, ConnectionCount};
+use auth::AccessControl;
+use tcp;
+use failure::Error;
+
+/// Splits a line of text up into words, handling double-quoted strings.
+///
+/// Returns each word, and whether it was quoted.
+fn split_words(line: &str) -> Result<Vec<(String, bool)>, Error> {
+    let mut ret = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        match chars.peek().cloned() {
+            None => break,
+            Some('"') => {
+                chars.next();
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            match chars.next() {
+                                Some(c) => word.push(c),
+                                None => bail!("unterminated escape")
+                            }
+                        },
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated string")
+                    }
+                }
+                ret.push((word, true));
+            },
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                ret.push((word, false));
+            }
+        }
+    }
+    Ok(ret)
+}
+/// Parses a text command into an OSC message.
+pub fn parse_text(line: &str) -> Result<OscMessage, Error> {
+    let mut words = split_words(line)?.into_iter();
+    let addr = match words.next() {
+        Some((a, _)) => a,
+        None => bail!("empty command")
+    };
+    if !addr.starts_with('/') {
+        bail!("address '{}' should start with a /", addr);
+    }
+    let args = words.map(|(word, quoted)| {
+        if quoted {
+            OscType::String(word)
+        }
+        else if let Ok(i) = word.parse::<i32>() {
+            OscType::Int(i)
+        }
+        else if let Ok(f) = word.parse::<f32>() {
+            OscType::Float(f)
+        }
+        else if word == "true" || word == "false" {
+            OscType::Bool(word == "true")
+        }
+        else {
+            OscType::String(word)
+        }
+    }).collect::<Vec<_>>();
+    Ok(OscMessage {
+        addr,
+        args: if !args.is_empty() { Some(args) } else { None }
+    })
+}
+/// Formats an OSC message as a line of text, in the same format `parse_text` accepts.
+pub fn format_text(msg: &OscMessage) -> String {
+    let mut ret = msg.addr.clone();
+    for arg in msg.args.iter().flat_map(|a| a.iter()) {
+        ret.push(' ');
+        match *arg {
+            OscType::Int(i) => ret.push_str(&i.to_string()),
+            OscType::Long(i) => ret.push_str(&i.to_string()),
+            OscType::Float(f) => ret.push_str(&f.to_string()),
+            OscType::Double(f) => ret.push_str(&f.to_string()),
+            OscType::Bool(b) => ret.push_str(if b { "true" } else { "false" }),
+            OscType::String(ref s) => {
+                ret.push('"');
+                ret.push_str(&s.replace('\\', "\\\\").replace('"', "\\\""));
+                ret.push('"');
+            },
+            ref x => ret.push_str(&format!("{:?}", x))
+        }
+    }
+    ret.push('\n');
+    ret
+}
+
+pub struct UnixContext {
+    pub path: String,
+    pub kind: UnixKind,
+    /// Permissions to give the socket, as an octal string (e.g. `660`).
+    pub mode: Option<String>,
+    pub tx: Sender<Message>,
+    pub acl: Arc<AccessControl>,
+    /// Whether the audio engine is running (see `osc::process_msg`).
+    pub jack_up: Arc<AtomicBool>
+}
+impl UnixContext {
+    /// Removes a stale socket left over from a previous run.
+    fn remove_stale(path: &Path) -> Result<(), Error> {
+        if let Ok(md) = fs::symlink_metadata(path) {
+            if !md.file_type().is_socket() {
+                bail!("{} exists, and isn't a socket", path.display());
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+    fn set_mode(&self, path: &Path) -> Result<(), Error> {
+        if let Some(ref mode) = self.mode {
+            let mode = u32::from_str_radix(mode, 8)
+                .map_err(|e| format_err!("invalid socket mode '{}': {}", mode, e))?;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+    /// Binds a socket to `path` using `bind`, with its mode already set by the
+    /// time anyone else can connect to it.
+    ///
+    /// The socket is bound inside a directory that only we can get into, has
+    /// its mode set there, and is then moved into place.
+    fn bind<T, F: FnOnce(&Path) -> io::Result<T>>(&self, path: &Path, bind: F) -> Result<T, Error> {
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new(".")
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_dir = dir.join(format!(".{}.{}.tmp", name, ::std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&tmp_dir)
+            .map_err(|e| format_err!("failed creating '{}': {}", tmp_dir.display(), e))?;
+        let tmp = tmp_dir.join("socket");
+        let ret = (|| -> Result<T, Error> {
+            let ret = bind(&tmp)?;
+            self.set_mode(&tmp)?;
+            fs::rename(&tmp, path)?;
+            Ok(ret)
+        })();
+        let _ = fs::remove_file(&tmp);
+        let _ = fs::remove_dir(&tmp_dir);
+        ret
+    }
+    fn handle_stream(stream: UnixStream, kind: UnixKind, name: String, tx: Sender<Message>, acl: Arc<AccessControl>, jack_up: Arc<AtomicBool>) -> Result<(), Error> {
+        info!("Accepted Unix socket connection ({})", name);
+        let format = match kind {
+            UnixKind::Text => StreamFormat::Text,
+            _ => StreamFormat::Osc(Framing::Slip)
+        };
+        let writer = stream.try_clone()?;
+        writer.set_write_timeout(Some(Duration::from_millis(osc::STREAM_WRITE_TIMEOUT_MS)))?;
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let reply = Replier::Stream {
+            stream: Arc::new(Mutex::new(writer)),
+            format, name
+        };
+        let mut reader = BufReader::new(stream);
+        if let UnixKind::Text = kind {
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_text(&line) {
+                    Ok(msg) => osc::process_msg(&tx, &acl, &jack_up, msg, &reply),
+                    Err(e) => {
+                        reply.send(OscMessage {
+                            addr: "/error".into(),
+                            args: Some(vec![OscType::String(e.to_string())])
+                        });
+                    }
+                }
+            }
+        }
+        else {
+            while let Some(frame) = tcp::read_frame(Framing::Slip, &mut reader)? {
+                osc::process_packet(&tx, &acl, &jack_up, &frame, &reply);
+            }
+        }
+        info!("Unix socket connection closed");
+        Ok(())
+    }
+    pub fn run(&mut self) -> Result<(), Error> {
+        let path = Path::new(&self.path).to_owned();
+        Self::remove_stale(&path)?;
+        match self.kind {
+            UnixKind::Datagram => {
+                let sock = Arc::new(self.bind(&path, |p| UnixDatagram::bind(p))?);
+                let mut buf = [0u8; rosc::decoder::MTU];
+                loop {
+                    let (size, addr) = match sock.recv_from(&mut buf) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            error!("Error receiving on Unix socket {}: {}", path.display(), e);
+                            ::std::thread::sleep(Duration::from_millis(1000));
+                            continue;
+                        }
+                    };
+                    let reply = Replier::UnixDatagram {
+                        sock: sock.clone(),
+                        path: addr.as_pathname().map(|p| p.to_owned())
+                    };
+                    info!("Received packet from {} (size: {})", reply.describe(), size);
+                    osc::process_packet(&self.tx, &self.acl, &self.jack_up, &buf[..size], &reply);
+                }
+            },
+            kind => {
+                let listener = self.bind(&path, |p| UnixListener::bind(p))?;
+                let mut conn_id = 0;
+                let connections = ConnectionCount::default();
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(s) => s,
+                        Err(e) => {
+                            error!("Error accepting Unix socket connection: {}", e);
+                            ::std::thread::sleep(Duration::from_millis(1000));
+                            continue;
+                        }
+                    };
+                    let guard = match connections.acquire() {
+                        Some(g) => g,
+                        None => {
+                            warn!("Turning away connection to Unix socket {}: already handling {} connections", path.display(), osc::MAX_CONNECTIONS);
+                            continue;
+                        }
+                    };
+                    conn_id += 1;
+                    let name = format!("unix:{}#{}", path.display(), conn_id);
+                    let tx = self.tx.clone();
+                    let acl = self.acl.clone();
+                    let jack_up = self.jack_up.clone();
+                    ::std::thread::spawn(move || {
+                        let _guard = guard;
+                        if let Err(e) = Self::handle_stream(stream, kind, name, tx, acl, jack_up) {
+                            warn!("Error on Unix socket connection: {}", e);
+                        }
+                    });
+                }
+                Ok(())
+            }
+        }
+    }
+}